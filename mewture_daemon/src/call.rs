@@ -0,0 +1,135 @@
+use mewture_shared::CallSettings;
+
+use crate::error::MewtureError;
+use crate::pulseaudio_handler::PulseAudioHandler;
+
+/// Notices apps recording from the source, which is as close as we get to knowing there's a
+/// call going on, and says when to mute or unmute as a call starts or ends if the config says so.
+pub struct CallWatcher {
+    settings: CallSettings,
+    /// The apps recording when last checked, sorted. `None` until the first check, so a call
+    /// that was already going on when the daemon started doesn't count as starting.
+    apps: Option<Vec<String>>
+}
+
+impl CallWatcher {
+    pub fn new(settings: CallSettings) -> Self {
+        Self { settings, apps: None }
+    }
+
+    /// Whether anything (that counts) is recording from the source.
+    pub fn in_call(&self) -> bool {
+        self.apps.as_ref().is_some_and(|apps| !apps.is_empty())
+    }
+
+    /// Looks at who's recording now, returning the mute state that calls for, if any. When the
+    /// first app starts, that's unmuted if any of the apps recording asks for it, unless the
+    /// screen is `locked`; when the last one stops, muted if any of the apps that were recording
+    /// asks for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the recording apps can't be listed.
+    pub fn check(
+        &mut self,
+        pulseaudio: &mut PulseAudioHandler,
+        current_mute_state: bool,
+        locked: bool,
+        debug: bool
+    ) -> Result<Option<bool>, MewtureError> {
+        let apps = pulseaudio.recording_apps()?;
+        if debug && self.apps.as_ref() != Some(&apps) {
+            println!("Recording from the source: {:?}", apps);
+        }
+
+        Ok(self.update(apps, current_mute_state, locked))
+    }
+
+    /// `check` once the recording apps are known.
+    fn update(&mut self, apps: Vec<String>, current_mute_state: bool, locked: bool) -> Option<bool> {
+        let apps: Vec<String> = apps.into_iter()
+            .filter(|app| self.settings.counts(app))
+            .collect();
+        // Nothing to compare the first check to.
+        let previous = self.apps.replace(apps.clone())?;
+        if apps == previous {
+            return None;
+        }
+
+        let started = previous.is_empty() && !apps.is_empty();
+        let ended = !previous.is_empty() && apps.is_empty();
+
+        let mut wanted = None;
+        if started && current_mute_state && !locked && apps.iter().any(|app| self.settings.auto_unmute_for(app)) {
+            eprintln!("Call started ({}), unmuting", apps.join(", "));
+            wanted = Some(false);
+        }
+        if ended && !current_mute_state && previous.iter().any(|app| self.settings.auto_mute_for(app)) {
+            eprintln!("Call ended ({}), muting", previous.join(", "));
+            wanted = Some(true);
+        }
+
+        wanted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mewture_shared::AppRule;
+
+    fn watcher(auto_mute: bool, auto_unmute: bool) -> CallWatcher {
+        CallWatcher::new(CallSettings { auto_mute, auto_unmute, ..Default::default() })
+    }
+
+    fn apps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn leaves_a_call_going_on_at_start_alone() {
+        let mut calls = watcher(true, true);
+        assert_eq!(calls.update(apps(&["Firefox"]), true, false), None);
+        assert!(calls.in_call());
+        assert_eq!(calls.update(apps(&["Firefox"]), true, false), None);
+    }
+
+    #[test]
+    fn unmutes_when_a_call_starts_and_mutes_when_it_ends() {
+        let mut calls = watcher(true, true);
+        assert_eq!(calls.update(apps(&[]), true, false), None);
+        assert!(!calls.in_call());
+        assert_eq!(calls.update(apps(&["Firefox"]), true, false), Some(false));
+        assert_eq!(calls.update(apps(&["Firefox", "Zoom"]), false, false), None);
+        assert_eq!(calls.update(apps(&[]), false, false), Some(true));
+    }
+
+    #[test]
+    fn leaves_the_state_alone_when_it_already_fits() {
+        let mut calls = watcher(true, true);
+        calls.update(apps(&[]), false, false);
+        assert_eq!(calls.update(apps(&["Firefox"]), false, false), None);
+        assert_eq!(calls.update(apps(&[]), true, false), None);
+    }
+
+    #[test]
+    fn doesnt_unmute_while_locked() {
+        let mut calls = watcher(true, true);
+        calls.update(apps(&[]), true, true);
+        assert_eq!(calls.update(apps(&["Firefox"]), true, true), None);
+    }
+
+    #[test]
+    fn follows_app_rules_and_ignores() {
+        let mut settings = CallSettings { auto_unmute: false, ignore: apps(&["pavucontrol"]), ..Default::default() };
+        settings.apps.insert("zoom".to_string(), AppRule { auto_unmute: Some(true), ..Default::default() });
+        let mut calls = CallWatcher::new(settings);
+        calls.update(apps(&[]), true, false);
+
+        assert_eq!(calls.update(apps(&["pavucontrol"]), true, false), None);
+        assert!(!calls.in_call());
+        assert_eq!(calls.update(apps(&["Firefox"]), true, false), None);
+        calls.update(apps(&[]), true, false);
+        assert_eq!(calls.update(apps(&["Zoom"]), true, false), Some(false));
+    }
+}
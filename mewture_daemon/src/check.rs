@@ -0,0 +1,255 @@
+use mewture_shared::{Config, FeedbackSettings};
+use pulser::simple::PulseAudio;
+use std::fs;
+use std::path::Path;
+
+/// How close (0.0 to 1.0) a source name has to be to the configured one to be suggested.
+const SUGGESTION_THRESHOLD: f64 = 0.6;
+
+/// What `--check-config` found.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Things that will stop the daemon from working.
+    pub problems: Vec<String>,
+    /// Things that look off, or couldn't be checked.
+    pub warnings: Vec<String>
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Prints everything found, problems first.
+    pub fn print(&self) {
+        for problem in &self.problems {
+            eprintln!("error: {}", problem);
+        }
+        for warning in &self.warnings {
+            eprintln!("warning: {}", warning);
+        }
+
+        if self.is_ok() {
+            println!("Config OK");
+        } else {
+            println!("{} problem(s) found", self.problems.len());
+        }
+    }
+}
+
+/// Checks the config file at `path`: that it loads, has no unknown keys, that the button's
+/// serial port is there and can be opened, and that the audio source exists.
+pub fn check_config(path: &Path) -> Report {
+    let mut report = Report::default();
+
+    let config = match load(path, &mut report) {
+        Some(config) => config,
+        None => return report
+    };
+
+    // A networked button has no port here to check.
+    if config.tcp.is_none() {
+        check_serial_port(&config, &mut report);
+    }
+    check_audio_source(&config, &mut report);
+    if let Some(feedback) = &config.feedback {
+        check_feedback(feedback, &mut report);
+    }
+
+    report
+}
+
+/// Loads the config at `path`, reporting its unknown keys, or why it can't be loaded.
+fn load(path: &Path, report: &mut Report) -> Option<Config> {
+    let (config, unknown_keys) = match mewture_shared::load_config_with_unknown_keys(path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.problems.push(format!("{}: {}", path.display(), e));
+            return None;
+        }
+    };
+
+    for key in unknown_keys {
+        report.problems.push(format!("{}: unknown key `{}`", path.display(), key));
+    }
+
+    Some(config)
+}
+
+/// Checks that the configured feedback sounds are there.
+fn check_feedback(feedback: &FeedbackSettings, report: &mut Report) {
+    for sound in [&feedback.mute_sound, &feedback.unmute_sound].into_iter().flatten() {
+        if !sound.is_file() {
+            report.problems.push(format!("Feedback sound {} doesn't exist", sound.display()));
+        }
+    }
+}
+
+/// Checks that the button's port exists and that we're allowed to open it.
+fn check_serial_port(config: &Config, report: &mut Report) {
+    let port_path = match config.device.as_ref().and_then(|selector| selector.resolve()) {
+        Some(port_path) => port_path,
+        None => match &config.device {
+            // Nothing else to look for.
+            Some(selector) if config.serial_port.is_empty() => {
+                report.problems.push(format!(
+                    "{} can't be found, is the button plugged in? Run `mewture_setup` to pick it again.",
+                    selector
+                ));
+                return;
+            },
+            Some(selector) => {
+                report.warnings.push(format!("{} is not plugged in, checking serial_port instead", selector));
+                config.serial_port.clone()
+            },
+            None => config.serial_port.clone()
+        }
+    };
+
+    if let Err(e) = fs::metadata(&port_path) {
+        report.problems.push(format!(
+            "Serial port `{}` can't be found ({}), is the button plugged in? Run `mewture_setup` to pick it again.",
+            port_path,
+            e
+        ));
+        return;
+    }
+
+    if let Some(problem) = mewture_shared::diagnose_port_access(&port_path) {
+        report.problems.push(problem);
+    }
+}
+
+/// Checks that the configured audio source exists, suggesting close matches if it doesn't.
+fn check_audio_source(config: &Config, report: &mut Report) {
+    if let Some(cookie) = &config.pulseaudio.cookie {
+        if !cookie.is_file() {
+            report.problems.push(format!("PulseAudio cookie {} doesn't exist", cookie.display()));
+        }
+    }
+
+    config.pulseaudio.export();
+    let pulseaudio = PulseAudio::connect(Some("Mewture Button Check"));
+    let sources = match pulseaudio.get_source_info_list() {
+        Ok(sources) => sources,
+        Err(e) => {
+            report.warnings.push(format!(
+                "Could not list sources on {}, audio_device_name was not checked: {}",
+                config.pulseaudio.describe(),
+                e
+            ));
+            return;
+        }
+    };
+
+    let names: Vec<String> = sources.into_iter().filter_map(|source| source.name).collect();
+    if names.contains(&config.audio_device_name) {
+        return;
+    }
+
+    let suggestions = suggest(&config.audio_device_name, &names);
+    let mut problem = format!("Audio source `{}` doesn't exist.", config.audio_device_name);
+    if suggestions.is_empty() {
+        problem.push_str(" Run `mewture_setup` to pick it again.");
+    } else {
+        problem.push_str(" Did you mean:");
+        for suggestion in suggestions {
+            problem.push_str(&format!("\n    {}", suggestion));
+        }
+    }
+    report.problems.push(problem);
+}
+
+/// The names closest to `wanted`, best first. Sources often only differ in a serial number or
+/// port suffix, so the top three are plenty.
+fn suggest(wanted: &str, names: &[String]) -> Vec<String> {
+    let mut scored: Vec<(f64, &String)> = names.iter()
+        .map(|name| (strsim::jaro_winkler(wanted, name), name))
+        .filter(|(score, _)| *score >= SUGGESTION_THRESHOLD)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    scored.into_iter().take(3).map(|(_, name)| name.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// Loads `content` as a config file, reporting into `report`.
+    fn load_str(content: &str, report: &mut Report) -> Option<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, content).unwrap();
+        load(&path, report)
+    }
+
+    #[test]
+    fn suggests_close_names_best_first() {
+        let sources = names(&[
+            "alsa_output.pci-0000_00_1f.3.analog-stereo.monitor",
+            "alsa_input.usb-Blue_Yeti-00.analog-stereo",
+            "alsa_input.usb-Blue_Yeti-01.analog-stereo",
+            "bluez_source.00_11_22"
+        ]);
+
+        assert_eq!(
+            suggest("alsa_input.usb-Blue_Yeti-02.analog-stereo", &sources),
+            names(&["alsa_input.usb-Blue_Yeti-00.analog-stereo", "alsa_input.usb-Blue_Yeti-01.analog-stereo", "alsa_output.pci-0000_00_1f.3.analog-stereo.monitor"])
+        );
+        assert!(suggest("zzz", &sources).is_empty());
+        assert!(suggest("anything", &[]).is_empty());
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let mut report = Report::default();
+        let config = load_str(
+            "audio_device_name = \"mic\"\nserial_port = \"/dev/ttyACM0\"\ncolour = \"red\"\n[serial]\nbaudrate = 9600\n",
+            &mut report
+        );
+
+        assert!(config.is_some());
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.problems[0].ends_with("unknown key `colour`"), "{:?}", report.problems);
+        assert!(report.problems[1].ends_with("unknown key `serial.baudrate`"), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn reports_configs_that_dont_load() {
+        let mut report = Report::default();
+        assert!(load_str("audio_device_name = ", &mut report).is_none());
+        assert_eq!(report.problems.len(), 1);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn describes_a_selector_with_no_port_to_fall_back_on() {
+        let mut report = Report::default();
+        let config = load_str("audio_device_name = \"mic\"\n[device]\nvendor_id = 0xfffe\nproduct_id = 0xfffd\n", &mut report).unwrap();
+        check_serial_port(&config, &mut report);
+
+        assert_eq!(report.problems, vec!["USB device fffe:fffd can't be found, is the button plugged in? Run `mewture_setup` to pick it again.".to_string()]);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn falls_back_on_the_serial_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = dir.path().join("ttyACM0");
+        fs::write(&port, "").unwrap();
+        let mut report = Report::default();
+        let config = load_str(
+            &format!("audio_device_name = \"mic\"\nserial_port = \"{}\"\n[device]\nvendor_id = 0xfffe\n", port.display()),
+            &mut report
+        ).unwrap();
+        check_serial_port(&config, &mut report);
+
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.warnings, vec!["USB device vendor fffe is not plugged in, checking serial_port instead".to_string()]);
+    }
+}
@@ -0,0 +1,169 @@
+use mewture_shared::NotificationSettings;
+use std::collections::HashMap;
+use std::time::Duration;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+/// How long the notification server gets to answer, so a stuck one can't hold up the daemon.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shows mute changes as desktop notifications, through `org.freedesktop.Notifications` on the
+/// session bus. Each one replaces the last, so toggling doesn't pile them up.
+pub struct DesktopNotifier {
+    settings: NotificationSettings,
+    /// Opened on first use, the session bus may not be up when the daemon starts.
+    connection: Option<Connection>,
+    /// Whether `connection` is the session bus, to be opened again after a failure.
+    session: bool,
+    /// The id of the notification being shown, 0 for none.
+    replaces_id: u32,
+    /// Whether the last attempt failed, to log a failure only once.
+    failing: bool
+}
+
+impl DesktopNotifier {
+    /// Notifies on `connection`, which can be any bus (a private one with a stand-in notification
+    /// server, say) and is kept after failures, or on the session bus when it's `None`.
+    pub fn new(settings: NotificationSettings, connection: Option<Connection>) -> Self {
+        let session = connection.is_none();
+        Self { settings, connection, session, replaces_id: 0, failing: false }
+    }
+
+    /// Shows that `source` (its description) was muted or unmuted.
+    /// Failures are logged and otherwise ignored, a missed notification isn't worth more.
+    pub async fn mute_changed(&mut self, muted: bool, source: &str) {
+        let (summary, icon) = match muted {
+            true => ("Microphone muted", "microphone-sensitivity-muted"),
+            false => ("Microphone unmuted", "microphone-sensitivity-high")
+        };
+
+        match tokio::time::timeout(NOTIFY_TIMEOUT, self.notify(summary, source, icon)).await {
+            Ok(Ok(id)) => {
+                self.replaces_id = id;
+                self.failing = false;
+            },
+            Ok(Err(e)) => self.failed(&e.to_string()),
+            Err(_) => self.failed("the notification server didn't answer")
+        }
+    }
+
+    async fn notify(&mut self, summary: &str, body: &str, icon: &str) -> zbus::Result<u32> {
+        let connection = match &self.connection {
+            Some(connection) => connection.clone(),
+            None => {
+                let connection = Connection::session().await?;
+                self.connection = Some(connection.clone());
+                connection
+            }
+        };
+
+        let mut hints: HashMap<&str, Value> = HashMap::new();
+        hints.insert("urgency", Value::U8(self.settings.urgency.level()));
+        hints.insert("category", Value::from("device"));
+
+        let reply = connection.call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &("Mewture Button", self.replaces_id, icon, summary, body, Vec::<&str>::new(), hints, self.settings.timeout_ms)
+        ).await?;
+
+        reply.body::<u32>()
+    }
+
+    fn failed(&mut self, error: &str) {
+        if !self.failing {
+            eprintln!("Could not show a desktop notification: {}", error);
+        }
+        self.failing = true;
+        // The bus may have gone away with the session, reconnect next time.
+        if self.session {
+            self.connection = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use mewture_shared::Urgency;
+    use tokio::net::UnixStream;
+    use tokio::sync::mpsc;
+    use zbus::zvariant::OwnedValue;
+    use zbus::{ConnectionBuilder, Guid, MessageStream, MessageType};
+
+    /// Both ends of a private connection, the server's and ours.
+    async fn private_bus() -> (Connection, Connection) {
+        let guid = Guid::generate();
+        let (server, client) = UnixStream::pair().unwrap();
+        tokio::try_join!(
+            ConnectionBuilder::unix_stream(server).server(&guid).p2p().build(),
+            ConnectionBuilder::unix_stream(client).p2p().build()
+        ).unwrap()
+    }
+
+    /// The arguments of `Notify`: app name, replaces_id, icon, summary, body, actions, hints, timeout.
+    type Notify = (String, u32, String, String, String, Vec<String>, HashMap<String, OwnedValue>, i32);
+
+    /// What a `Notify` call carried: replaces_id, icon, summary, body, hints, timeout.
+    type Notification = (u32, String, String, String, HashMap<String, OwnedValue>, i32);
+
+    /// A stand-in notification server on a private connection, handing over every notification
+    /// and answering with ids counting up from 1. Replies by hand, the object server ignores
+    /// calls addressed to a bus name on a private connection.
+    async fn notification_server() -> (Connection, mpsc::UnboundedReceiver<Notification>) {
+        let (server, client) = private_bus().await;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut calls = MessageStream::from(&server);
+        tokio::spawn(async move {
+            let mut id = 0u32;
+            while let Some(Ok(call)) = calls.next().await {
+                if call.message_type() != MessageType::MethodCall || call.member().as_deref() != Some("Notify") {
+                    continue;
+                }
+                let (_, replaces_id, icon, summary, body, _, hints, timeout): Notify = call.body().unwrap();
+                sender.send((replaces_id, icon, summary, body, hints, timeout)).unwrap();
+
+                id += 1;
+                server.reply(&call, &id).await.unwrap();
+            }
+        });
+
+        (client, receiver)
+    }
+
+    #[tokio::test]
+    async fn notifies_and_replaces_the_last_notification() {
+        let (connection, mut notifications) = notification_server().await;
+        let settings = NotificationSettings { urgency: Urgency::Critical, timeout_ms: 2000 };
+        let mut notifier = DesktopNotifier::new(settings, Some(connection));
+
+        notifier.mute_changed(true, "Blue Yeti").await;
+        let (replaces_id, icon, summary, body, hints, timeout) = notifications.recv().await.unwrap();
+        assert_eq!(replaces_id, 0);
+        assert_eq!(icon, "microphone-sensitivity-muted");
+        assert_eq!(summary, "Microphone muted");
+        assert_eq!(body, "Blue Yeti");
+        assert_eq!(u8::try_from(hints["urgency"].clone()).unwrap(), 2);
+        assert_eq!(timeout, 2000);
+
+        notifier.mute_changed(false, "Blue Yeti").await;
+        let (replaces_id, icon, summary, ..) = notifications.recv().await.unwrap();
+        assert_eq!(replaces_id, 1);
+        assert_eq!(icon, "microphone-sensitivity-high");
+        assert_eq!(summary, "Microphone unmuted");
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_server_that_doesnt_answer() {
+        // Nothing answers on the other end.
+        let (_server, connection) = private_bus().await;
+        let mut notifier = DesktopNotifier::new(NotificationSettings::default(), Some(connection));
+
+        notifier.mute_changed(true, "Blue Yeti").await;
+        assert!(notifier.failing);
+        assert_eq!(notifier.replaces_id, 0);
+    }
+}
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors the daemon can run into.
+///
+/// Every variant carries enough context to be logged on its own, and is classed as either
+/// retryable or fatal through `is_retryable`, so the main loop can decide what to do without
+/// looking at the error text.
+#[derive(Debug)]
+pub enum MewtureError {
+    /// Opening, reading from or writing to the transport (serial port or TCP connection) failed.
+    Transport {
+        port: String,
+        source: io::Error
+    },
+    /// The button went away (unplugged, reset, broken pipe).
+    Disconnected {
+        port: String
+    },
+    /// The audio backend (PulseAudio) returned an error.
+    Audio {
+        device: String,
+        message: String
+    },
+    /// The button sent a message we could not act upon.
+    Protocol {
+        message: String
+    },
+    /// The configuration could not be read or is invalid.
+    Config {
+        path: PathBuf,
+        message: String
+    }
+}
+
+impl MewtureError {
+    /// Whether the daemon can recover from this error by retrying (reconnecting, reading the
+    /// next message, polling again...), as opposed to bailing out.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MewtureError::Transport { source, .. } => {
+                // No amount of retrying will fix a permission problem.
+                source.kind() != io::ErrorKind::PermissionDenied
+            },
+            MewtureError::Disconnected { .. } => true,
+            MewtureError::Audio { .. } => true,
+            MewtureError::Protocol { .. } => true,
+            MewtureError::Config { .. } => false
+        }
+    }
+
+    /// Build a transport error from anything that converts into an `io::Error` (this includes
+    /// `serialport::Error`), turning a broken pipe, a reset connection or an I/O error on an
+    /// unplugged tty into `Disconnected`.
+    pub fn transport(port: &str, error: impl Into<io::Error>) -> Self {
+        let source: io::Error = error.into();
+        match source.kind() {
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted => {
+                MewtureError::Disconnected { port: port.to_string() }
+            },
+            _ if source.raw_os_error() == Some(libc::EIO) => {
+                MewtureError::Disconnected { port: port.to_string() }
+            },
+            _ => MewtureError::Transport { port: port.to_string(), source }
+        }
+    }
+
+    /// Build an audio error for the given device.
+    pub fn audio(device: &str, error: impl fmt::Display) -> Self {
+        MewtureError::Audio { device: device.to_string(), message: error.to_string() }
+    }
+}
+
+impl fmt::Display for MewtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MewtureError::Transport { port, source } => {
+                write!(f, "Button port `{}` error: {}", port, source)
+            },
+            MewtureError::Disconnected { port } => write!(f, "Button port `{}` disconnected", port),
+            MewtureError::Audio { device, message } => {
+                write!(f, "Audio device `{}` error: {}", device, message)
+            },
+            MewtureError::Protocol { message } => write!(f, "Protocol error: {}", message),
+            MewtureError::Config { path, message } => {
+                write!(f, "Config `{}` error: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl Error for MewtureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MewtureError::Transport { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transport(error: io::Error) -> MewtureError {
+        MewtureError::transport("/dev/ttyUSB0", error)
+    }
+
+    #[test]
+    fn broken_pipes_and_eio_are_disconnects() {
+        for error in [
+            io::Error::from(io::ErrorKind::BrokenPipe),
+            io::Error::from(io::ErrorKind::UnexpectedEof),
+            io::Error::from(io::ErrorKind::ConnectionReset),
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            io::Error::from_raw_os_error(libc::EIO)
+        ] {
+            let error = transport(error);
+            assert!(matches!(&error, MewtureError::Disconnected { port } if port == "/dev/ttyUSB0"), "{:?}", error);
+            assert!(error.is_retryable());
+        }
+    }
+
+    #[test]
+    fn other_io_errors_stay_transport_errors() {
+        let error = transport(io::Error::from(io::ErrorKind::TimedOut));
+        assert!(matches!(&error, MewtureError::Transport { source, .. } if source.kind() == io::ErrorKind::TimedOut));
+        assert!(error.is_retryable());
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn permission_denied_is_fatal() {
+        let error = transport(io::Error::from_raw_os_error(libc::EACCES));
+        assert!(matches!(error, MewtureError::Transport { .. }));
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn only_config_errors_are_fatal_otherwise() {
+        assert!(MewtureError::audio("default", "no such device").is_retryable());
+        assert!(MewtureError::Protocol { message: "garbage".to_string() }.is_retryable());
+        let config = MewtureError::Config { path: PathBuf::from("config.toml"), message: "bad".to_string() };
+        assert!(!config.is_retryable());
+        assert_eq!(config.to_string(), "Config `config.toml` error: bad");
+    }
+}
@@ -0,0 +1,191 @@
+use mewture_shared::FeedbackSettings;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::error::MewtureError;
+
+/// Sample rate of the built-in tones.
+const SAMPLE_RATE: u32 = 44100;
+
+/// Length of the built-in tones, in milliseconds.
+const TONE_MS: u32 = 120;
+
+/// How many sounds may be underway at once. Toggles beyond that go unheard rather than pile up
+/// `pactl`s.
+const MAX_PLAYING: usize = 2;
+
+/// A sound in the server's sample cache.
+#[derive(Clone)]
+struct Sample {
+    /// Name it's cached under.
+    name: &'static str,
+    /// WAV file it's uploaded from.
+    path: PathBuf
+}
+
+/// Plays a short sound on mute changes, for users who can't see the button.
+/// The sounds are uploaded to the server's sample cache once and played from there, on the
+/// configured or default sink, and never on one the muted source would pick up.
+/// `pactl` does the talking, as the PulseAudio bindings don't reach the sample cache, so the
+/// package depends on pulseaudio-utils, and `PULSE_SERVER` and `PULSE_COOKIE` apply to it as well.
+pub struct Feedback {
+    sink: Option<String>,
+    /// The source being muted, see `FeedbackSettings::heard_through`.
+    source: String,
+    mute: Sample,
+    unmute: Sample,
+    playing: Arc<Semaphore>,
+    /// Holds the built-in tones, removed on exit.
+    _tones: TempDir
+}
+
+impl Feedback {
+    /// Writes out the built-in tones that are needed and uploads both sounds.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a sound can't be written or uploaded.
+    pub async fn new(settings: &FeedbackSettings, source: &str) -> Result<Self, MewtureError> {
+        let tones = tempfile::tempdir()
+            .map_err(|e| MewtureError::audio("feedback", e))?;
+
+        let mute = Sample {
+            name: "mewture-mute",
+            path: sound_path(&settings.mute_sound, tones.path(), "mute.wav", 440.0)?
+        };
+        let unmute = Sample {
+            name: "mewture-unmute",
+            path: sound_path(&settings.unmute_sound, tones.path(), "unmute.wav", 880.0)?
+        };
+        upload(&mute).await?;
+        upload(&unmute).await?;
+
+        Ok(Self {
+            sink: settings.sink.clone(),
+            source: source.to_string(),
+            mute,
+            unmute,
+            playing: Arc::new(Semaphore::new(MAX_PLAYING)),
+            _tones: tones
+        })
+    }
+
+    /// Plays the sound for the new mute state, without waiting for it. Skipped when `MAX_PLAYING`
+    /// sounds are underway already.
+    pub fn mute_changed(&self, muted: bool) {
+        let permit = match self.playing.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => return
+        };
+        let sample = match muted {
+            true => self.mute.clone(),
+            false => self.unmute.clone()
+        };
+        let (sink, source) = (self.sink.clone(), self.source.clone());
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            // The default sink can change at any time, so it's looked up and checked every time.
+            let sink = match sink {
+                Some(sink) => sink,
+                None => match pactl(&["get-default-sink"]).await {
+                    Ok(sink) => sink.trim().to_string(),
+                    Err(e) => {
+                        eprintln!("Could not play {}: {}", sample.name, e);
+                        return;
+                    }
+                }
+            };
+            if FeedbackSettings::heard_through(&sink, &source) {
+                eprintln!("Not playing {}: {} would be heard through {}", sample.name, sink, source);
+                return;
+            }
+
+            if play(&sample, &sink).await.is_ok() {
+                return;
+            }
+
+            // The server may have restarted and lost its sample cache, upload it again.
+            let result = match upload(&sample).await {
+                Ok(_) => play(&sample, &sink).await,
+                Err(e) => Err(e)
+            };
+            if let Err(e) = result {
+                eprintln!("Could not play {}: {}", sample.name, e);
+            }
+        });
+    }
+}
+
+/// The configured sound, or a built-in tone of `frequency` Hz written to `dir`.
+fn sound_path(configured: &Option<PathBuf>, dir: &Path, file_name: &str, frequency: f32) -> Result<PathBuf, MewtureError> {
+    if let Some(path) = configured {
+        return Ok(path.clone());
+    }
+
+    let path = dir.join(file_name);
+    fs::write(&path, tone(frequency)).map_err(|e| MewtureError::audio("feedback", e))?;
+    Ok(path)
+}
+
+/// A short sine beep as a 16-bit mono WAV, faded in and out so it doesn't click.
+fn tone(frequency: f32) -> Vec<u8> {
+    let samples = SAMPLE_RATE * TONE_MS / 1000;
+    let fade = samples / 10;
+    let data_len = samples * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for i in 0..samples {
+        let envelope = (i.min(samples - i) as f32 / fade as f32).min(1.0);
+        let value = (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin() * envelope * 0.4;
+        wav.extend_from_slice(&((value * f32::from(i16::MAX)) as i16).to_le_bytes());
+    }
+
+    wav
+}
+
+async fn upload(sample: &Sample) -> Result<(), MewtureError> {
+    pactl(&["upload-sample", &sample.path.to_string_lossy(), sample.name]).await.map(|_| ())
+}
+
+async fn play(sample: &Sample, sink: &str) -> Result<(), MewtureError> {
+    pactl(&["play-sample", sample.name, sink]).await.map(|_| ())
+}
+
+/// Runs `pactl` with `args`, returning its output, or turning a failure into an error with its
+/// error output.
+async fn pactl(args: &[&str]) -> Result<String, MewtureError> {
+    let output = Command::new("pactl")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| MewtureError::audio("feedback", e))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(MewtureError::audio(
+            "feedback",
+            format!("pactl {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())
+        ))
+    }
+}
@@ -0,0 +1,212 @@
+use mewture_shared::HookSettings;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::pulseaudio_handler::AudioHealth;
+
+/// What the hooks last saw, to tell what changed.
+#[derive(Clone, PartialEq)]
+struct State {
+    /// The button's port or address, when it's connected.
+    button: Option<String>,
+    muted: bool,
+    health: AudioHealth
+}
+
+/// Runs the `[hooks]` commands when the button comes or goes, the source is muted or unmuted,
+/// or audio goes away. Commands run in the background, killed after the timeout, and are
+/// skipped when too many are running already.
+pub struct Hooks {
+    settings: HookSettings,
+    source: String,
+    running: Arc<Semaphore>,
+    last: Option<State>
+}
+
+impl Hooks {
+    pub fn new(settings: HookSettings, source: String) -> Self {
+        let running = Arc::new(Semaphore::new(settings.max_concurrent));
+        Self { settings, source, running, last: None }
+    }
+
+    /// Runs the commands for whatever changed since the last call. The first call only takes
+    /// note, except for running `connect` if the button is there already.
+    pub fn update(&mut self, button: Option<&str>, muted: bool, health: AudioHealth) {
+        let state = State { button: button.map(str::to_string), muted, health };
+        let last = match self.last.replace(state.clone()) {
+            Some(last) if last == state => return,
+            Some(last) => last,
+            None => State { button: None, muted, health }
+        };
+
+        match (&last.button, &state.button) {
+            (None, Some(_)) => self.run("connect", &self.settings.connect, &state),
+            (Some(_), None) => self.run("disconnect", &self.settings.disconnect, &last),
+            _ => {}
+        }
+        if last.muted != state.muted {
+            match state.muted {
+                true => self.run("mute", &self.settings.mute, &state),
+                false => self.run("unmute", &self.settings.unmute, &state)
+            }
+        }
+        if last.health == AudioHealth::Connected && state.health != AudioHealth::Connected {
+            self.run("audio_error", &self.settings.audio_error, &state);
+        }
+    }
+
+    fn run(&self, event: &str, command: &Option<String>, state: &State) {
+        let command = match command {
+            Some(command) => command.clone(),
+            None => return
+        };
+        let permit = match self.running.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                eprintln!("Skipping the {} hook, {} hooks are running already", event, self.settings.max_concurrent);
+                return;
+            }
+        };
+
+        let mut child = Command::new("sh");
+        child.arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .env("MEWTURE_EVENT", event)
+            .env("MEWTURE_MUTED", if state.muted { "1" } else { "0" })
+            .env("MEWTURE_SOURCE", &self.source)
+            .env("MEWTURE_BUTTON", state.button.as_deref().unwrap_or(""))
+            .env("MEWTURE_AUDIO", match state.health {
+                AudioHealth::Connected => "ok",
+                AudioHealth::NoDevice => "no-device",
+                AudioHealth::ServerDown => "server-down"
+            });
+        let timeout = self.settings.timeout();
+        let event = event.to_string();
+
+        tokio::spawn(async move {
+            // Held until the command is done.
+            let _permit = permit;
+            let mut child = match child.spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    eprintln!("Could not run the {} hook: {}", event, e);
+                    return;
+                }
+            };
+
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(Ok(status)) if status.success() => {},
+                Ok(Ok(status)) => eprintln!("The {} hook failed: {}", event, status),
+                Ok(Err(e)) => eprintln!("The {} hook failed: {}", event, e),
+                Err(_) => {
+                    eprintln!("The {} hook took longer than {:?}, killing it", event, timeout);
+                    let _ = child.kill().await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    /// Hooks that each append their event and environment to `events` in `dir`.
+    fn logging_hooks(dir: &TempDir) -> (Hooks, PathBuf) {
+        let log = dir.path().join("events");
+        let command = format!(
+            "echo \"$MEWTURE_EVENT,$MEWTURE_MUTED,$MEWTURE_BUTTON,$MEWTURE_AUDIO,$MEWTURE_SOURCE\" >> '{}'",
+            log.display()
+        );
+        let settings = HookSettings {
+            mute: Some(command.clone()),
+            unmute: Some(command.clone()),
+            connect: Some(command.clone()),
+            disconnect: Some(command.clone()),
+            audio_error: Some(command),
+            ..Default::default()
+        };
+
+        (Hooks::new(settings, "mic".to_string()), log)
+    }
+
+    /// The events logged, sorted as hooks run concurrently, once `count` are in (or a while has
+    /// passed). Clears the log for the next step.
+    async fn events(log: &Path, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut lines: Vec<String> = loop {
+            // Give hooks that shouldn't have run the chance to show up.
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let lines: Vec<String> = std::fs::read_to_string(log).unwrap_or_default().lines().map(str::to_string).collect();
+            if lines.len() >= count || Instant::now() >= deadline {
+                break lines;
+            }
+        };
+        let _ = std::fs::remove_file(log);
+
+        lines.sort();
+        lines
+    }
+
+    #[tokio::test]
+    async fn first_update_only_runs_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut hooks, log) = logging_hooks(&dir);
+        hooks.update(Some("/dev/ttyACM0"), true, AudioHealth::NoDevice);
+        assert_eq!(events(&log, 1).await, vec!["connect,1,/dev/ttyACM0,no-device,mic"]);
+
+        let (mut hooks, log) = logging_hooks(&dir);
+        hooks.update(None, true, AudioHealth::ServerDown);
+        assert_eq!(events(&log, 0).await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn runs_mute_and_unmute_on_changes_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut hooks, log) = logging_hooks(&dir);
+        hooks.update(Some("127.0.0.1:4000"), false, AudioHealth::Connected);
+        events(&log, 1).await;
+
+        hooks.update(Some("127.0.0.1:4000"), true, AudioHealth::Connected);
+        assert_eq!(events(&log, 1).await, vec!["mute,1,127.0.0.1:4000,ok,mic"]);
+        hooks.update(Some("127.0.0.1:4000"), true, AudioHealth::Connected);
+        assert_eq!(events(&log, 0).await, Vec::<String>::new());
+        hooks.update(Some("127.0.0.1:4000"), false, AudioHealth::Connected);
+        assert_eq!(events(&log, 1).await, vec!["unmute,0,127.0.0.1:4000,ok,mic"]);
+    }
+
+    #[tokio::test]
+    async fn disconnect_gets_the_button_that_went() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut hooks, log) = logging_hooks(&dir);
+        hooks.update(Some("/dev/ttyACM0"), false, AudioHealth::Connected);
+        events(&log, 1).await;
+
+        hooks.update(None, true, AudioHealth::Connected);
+        assert_eq!(events(&log, 2).await, vec!["disconnect,0,/dev/ttyACM0,ok,mic", "mute,1,,ok,mic"]);
+        hooks.update(Some("/dev/ttyACM1"), true, AudioHealth::Connected);
+        assert_eq!(events(&log, 1).await, vec!["connect,1,/dev/ttyACM1,ok,mic"]);
+    }
+
+    #[tokio::test]
+    async fn audio_error_only_when_audio_goes_away() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut hooks, log) = logging_hooks(&dir);
+        hooks.update(None, false, AudioHealth::Connected);
+
+        hooks.update(None, false, AudioHealth::ServerDown);
+        assert_eq!(events(&log, 1).await, vec!["audio_error,0,,server-down,mic"]);
+        hooks.update(None, false, AudioHealth::NoDevice);
+        assert_eq!(events(&log, 0).await, Vec::<String>::new());
+        hooks.update(None, false, AudioHealth::Connected);
+        hooks.update(None, false, AudioHealth::NoDevice);
+        assert_eq!(events(&log, 1).await, vec!["audio_error,0,,no-device,mic"]);
+    }
+}
@@ -0,0 +1,283 @@
+use mewture_shared::DeviceSelector;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use udev::{Device, EventType, MonitorBuilder, MonitorSocket};
+
+/// What happened to the watched device.
+#[derive(Debug)]
+pub enum HotplugEvent {
+    /// The device (re)appeared at the given device node.
+    Added(PathBuf),
+    /// The device went away.
+    Removed
+}
+
+/// Describes the button's tty, so it can be found and recognized when it comes and goes.
+/// Starts from the configured path (a `/dev/serial/by-id/...` link or a plain device node) and
+/// device selector, and learns the USB vendor ID, product ID and serial number once the device
+/// has been seen, so it's still recognized if it comes back as another node. The manufacturer
+/// and product names only come from the selector.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceMatcher {
+    pub path: String,
+    pub selector: Option<DeviceSelector>,
+    /// What's known of the button's USB identity, the selector's plus what's been learned.
+    pub identity: DeviceSelector
+}
+
+impl DeviceMatcher {
+    pub fn new(path: &str, selector: Option<DeviceSelector>) -> Self {
+        let selector = selector.filter(|selector| !selector.is_empty());
+        let identity = selector.clone().unwrap_or_default();
+
+        Self { path: path.to_string(), selector, identity }
+    }
+
+    /// Where to open the button: the first port matching the selector if there is one,
+    /// the configured path otherwise.
+    pub fn resolve(&self) -> String {
+        self.selector.as_ref()
+            .and_then(DeviceSelector::resolve)
+            .unwrap_or_else(|| self.path.clone())
+    }
+
+    /// A human-readable description of what we're looking for.
+    pub fn describe(&self) -> String {
+        match &self.selector {
+            Some(selector) => selector.to_string(),
+            None => self.path.clone()
+        }
+    }
+
+    /// Fills in whatever USB identity is still unknown from the device behind `port_path`.
+    pub fn learn(&mut self, port_path: &str) {
+        let device = match std::fs::canonicalize(port_path) {
+            Ok(node) => match node.file_name() {
+                Some(sysname) => Device::from_subsystem_sysname(
+                    "tty".to_string(),
+                    sysname.to_string_lossy().to_string()
+                ).ok(),
+                None => None
+            },
+            Err(_) => None
+        };
+
+        let found = device.and_then(|device| {
+            DeviceSelector::from_udev_properties(|name| device.property_value(name).and_then(OsStr::to_str))
+        });
+        if let Some(found) = found {
+            self.identity.vendor_id = self.identity.vendor_id.or(found.vendor_id);
+            self.identity.product_id = self.identity.product_id.or(found.product_id);
+            self.identity.serial_number = self.identity.serial_number.take().or(found.serial_number);
+        }
+    }
+
+    /// Whether the device is the button: either by path (device node or one of its links), or by
+    /// its USB identity, see `matches_properties`.
+    pub fn matches(&self, device: &Device) -> bool {
+        if device.devnode() == Some(Path::new(&self.path)) {
+            return true;
+        }
+
+        self.matches_properties(|name| device.property_value(name).and_then(OsStr::to_str))
+    }
+
+    /// Whether the udev properties given by `property` name the button: `DEVLINKS` has the
+    /// configured path, or every USB identity field known (at least one) is the same, read the
+    /// way setup reads them through `serialport`.
+    fn matches_properties<'a>(&self, property: impl Fn(&str) -> Option<&'a str>) -> bool {
+        if let Some(links) = property("DEVLINKS") {
+            if links.split_whitespace().any(|link| link == self.path) {
+                return true;
+            }
+        }
+
+        DeviceSelector::from_udev_properties(property)
+            .is_some_and(|device| self.identity.matches_identity(&device))
+    }
+}
+
+/// Watches udev for the button's tty being plugged in or removed.
+/// If udev can't be listened to, `next` never returns and the caller's fallback polling takes over.
+pub struct Hotplug {
+    socket: Option<AsyncFd<MonitorSocket>>,
+    matcher: DeviceMatcher
+}
+
+impl Hotplug {
+    pub fn new(matcher: DeviceMatcher) -> Self {
+        let socket = MonitorBuilder::new()
+            .and_then(|builder| builder.match_subsystem("tty"))
+            .and_then(|builder| builder.listen())
+            .and_then(|socket| {
+                // SAFETY: the monitor owns its socket, which stays open until it's dropped, along
+                // with the `AsyncFd` it's moved into.
+                unsafe { AsyncFd::register_with_interest(socket, Interest::READABLE) }.map_err(std::io::Error::from)
+            });
+
+        match socket {
+            Ok(socket) => Self { socket: Some(socket), matcher },
+            Err(e) => {
+                eprintln!("Error listening for udev events, falling back to polling: {}", e);
+                Self { socket: None, matcher }
+            }
+        }
+    }
+
+    /// Where to open the button right now, see `DeviceMatcher::resolve`.
+    pub fn resolve(&self) -> String {
+        self.matcher.resolve()
+    }
+
+    /// A human-readable description of the button.
+    pub fn describe(&self) -> String {
+        self.matcher.describe()
+    }
+
+    /// Learns the USB identity of the button from the port it was just opened at.
+    pub fn learn(&mut self, port_path: &str) {
+        self.matcher.learn(port_path);
+    }
+
+    /// Waits for the button to be added or removed.
+    pub async fn next(&mut self) -> HotplugEvent {
+        loop {
+            let socket = match &self.socket {
+                Some(socket) => socket,
+                None => std::future::pending().await
+            };
+
+            let mut guard = match socket.readable().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    eprintln!("Error reading udev events, falling back to polling: {}", e);
+                    self.socket = None;
+                    continue;
+                }
+            };
+
+            // Drain everything queued up, the last event about the button wins.
+            let mut found: Option<HotplugEvent> = None;
+            for event in guard.get_inner().iter() {
+                if !self.matcher.matches(&event) {
+                    continue;
+                }
+
+                match event.event_type() {
+                    EventType::Add => {
+                        if let Some(node) = event.devnode() {
+                            found = Some(HotplugEvent::Added(node.to_path_buf()));
+                        }
+                    },
+                    EventType::Remove => found = Some(HotplugEvent::Removed),
+                    _ => {}
+                }
+            }
+            guard.clear_ready();
+
+            if let Some(event) = found {
+                return event;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn matcher(selector: DeviceSelector) -> DeviceMatcher {
+        DeviceMatcher::new("/dev/serial/by-id/usb-Mewture_Button-if00", Some(selector))
+    }
+
+    fn matches(matcher: &DeviceMatcher, properties: &[(&str, &str)]) -> bool {
+        let properties: HashMap<&str, &str> = properties.iter().copied().collect();
+        matcher.matches_properties(|name| properties.get(name).copied())
+    }
+
+    const BUTTON: &[(&str, &str)] = &[
+        ("ID_BUS", "usb"),
+        ("ID_VENDOR_ID", "2341"),
+        ("ID_MODEL_ID", "8036"),
+        ("ID_SERIAL_SHORT", "A1B2"),
+        ("ID_VENDOR_ENC", "Mewture\\x20Labs"),
+        ("ID_MODEL_ENC", "Mute\\x20Button")
+    ];
+
+    #[test]
+    fn matches_by_link() {
+        let matcher = DeviceMatcher::new("/dev/serial/by-id/usb-Mewture_Button-if00", None);
+        assert!(matches(&matcher, &[("DEVLINKS", "/dev/serial/by-path/pci-0 /dev/serial/by-id/usb-Mewture_Button-if00")]));
+        assert!(!matches(&matcher, BUTTON));
+    }
+
+    #[test]
+    fn matches_a_vendor_id_alone() {
+        let matcher = matcher(DeviceSelector { vendor_id: Some(0x2341), ..Default::default() });
+        assert!(matches(&matcher, BUTTON));
+        assert!(!matches(&matcher, &[("ID_BUS", "usb"), ("ID_VENDOR_ID", "1a86"), ("ID_MODEL_ID", "8036")]));
+    }
+
+    #[test]
+    fn matches_a_serial_number_alone() {
+        let matcher = matcher(DeviceSelector { serial_number: Some("A1B2".to_string()), ..Default::default() });
+        assert!(matches(&matcher, BUTTON));
+        assert!(!matches(&matcher, &[("ID_BUS", "usb"), ("ID_VENDOR_ID", "2341"), ("ID_MODEL_ID", "8036"), ("ID_SERIAL_SHORT", "C3D4")]));
+        assert!(!matches(&matcher, &[]));
+    }
+
+    #[test]
+    fn matches_names_unescaped() {
+        let matcher = matcher(DeviceSelector {
+            manufacturer: Some("Mewture Labs".to_string()),
+            product: Some("Mute Button".to_string()),
+            ..Default::default()
+        });
+        assert!(matches(&matcher, BUTTON));
+        assert!(!matches(&matcher, &[
+            ("ID_BUS", "usb"),
+            ("ID_VENDOR_ID", "2341"),
+            ("ID_MODEL_ID", "8036"),
+            ("ID_VENDOR_ENC", "Mewture\\x20Labs"),
+            ("ID_MODEL_ENC", "Other")
+        ]));
+    }
+
+    #[test]
+    fn matches_names_from_the_hardware_database() {
+        // What serialport reports, and setup writes, for a device that doesn't name itself.
+        let matcher = matcher(DeviceSelector {
+            manufacturer: Some("QinHeng Electronics".to_string()),
+            product: Some("CH340 serial converter".to_string()),
+            ..Default::default()
+        });
+        assert!(matches(&matcher, &[
+            ("ID_BUS", "usb"),
+            ("ID_VENDOR_ID", "1a86"),
+            ("ID_MODEL_ID", "7523"),
+            ("ID_VENDOR_FROM_DATABASE", "QinHeng Electronics"),
+            ("ID_MODEL_FROM_DATABASE", "CH340 serial converter")
+        ]));
+    }
+
+    #[test]
+    fn needs_every_field_set() {
+        let matcher = matcher(DeviceSelector {
+            vendor_id: Some(0x2341),
+            product_id: Some(0x8036),
+            serial_number: Some("A1B2".to_string()),
+            ..Default::default()
+        });
+        assert!(matches(&matcher, BUTTON));
+        assert!(!matches(&matcher, &[("ID_BUS", "usb"), ("ID_VENDOR_ID", "2341"), ("ID_MODEL_ID", "8036"), ("ID_SERIAL_SHORT", "C3D4")]));
+        assert!(!matches(&matcher, &[("ID_BUS", "usb"), ("ID_VENDOR_ID", "2341"), ("ID_MODEL_ID", "8036")]));
+    }
+
+    #[test]
+    fn matches_nothing_without_an_identity() {
+        assert!(!matches(&DeviceMatcher::new("/dev/ttyACM0", None), BUTTON));
+    }
+}
@@ -0,0 +1,361 @@
+use futures_util::stream::{BoxStream, SelectAll};
+use futures_util::StreamExt;
+use mewture_shared::LockSettings;
+use std::collections::HashMap;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+use zbus::{Connection, MatchRule, MessageStream, MessageType};
+
+/// Mutes the source while the screen is locked, going by logind's `LockedHint` for the user's
+/// graphical session and by screensavers' `ActiveChanged` signal, whichever are available.
+pub struct ScreenLock {
+    settings: LockSettings,
+    /// Lock state changes from every bus we could listen on.
+    events: SelectAll<BoxStream<'static, bool>>,
+    locked: bool,
+    /// Whether the last lock or unlock is yet to be acted on, PulseAudio may be away when it comes.
+    pending: bool,
+    /// The mute state from before the screen was locked, to put back on unlock.
+    muted_before: Option<bool>
+}
+
+impl ScreenLock {
+    /// Listens on the system and session buses, as the settings ask.
+    pub async fn new(settings: LockSettings) -> Self {
+        let system = match settings.logind {
+            true => log_failure("system", Connection::system().await),
+            false => None
+        };
+        let session = match settings.screensaver {
+            true => log_failure("session", Connection::session().await),
+            false => None
+        };
+
+        Self::with_connections(settings, system, session).await
+    }
+
+    /// Listens for logind on `system` and for the screensaver on `session`, either of which can
+    /// be any bus (a private one with stand-ins for logind and the screensaver, say).
+    pub async fn with_connections(settings: LockSettings, system: Option<Connection>, session: Option<Connection>) -> Self {
+        let mut events = SelectAll::new();
+
+        if let Some(system) = system {
+            match logind_events(&system).await {
+                Ok(stream) => events.push(stream),
+                Err(e) => eprintln!("Not following logind's lock state: {}", e)
+            }
+        }
+        if let Some(session) = session {
+            match screensaver_events(&session).await {
+                Ok(stream) => events.push(stream),
+                Err(e) => eprintln!("Not following the screensaver: {}", e)
+            }
+        }
+
+        Self { settings, events, locked: false, pending: false, muted_before: None }
+    }
+
+    /// Waits for the screen to be locked or unlocked, returning whether it's locked now.
+    /// Never returns when there's nothing to listen to.
+    pub async fn next(&mut self) -> bool {
+        loop {
+            match self.events.next().await {
+                // logind and the screensaver usually both say so.
+                Some(locked) if locked != self.locked => {
+                    eprintln!("Screen {}", if locked { "locked" } else { "unlocked" });
+                    self.locked = locked;
+                    self.pending = true;
+                    return locked;
+                },
+                Some(_) => {},
+                None => std::future::pending().await
+            }
+        }
+    }
+
+    /// The mute state the last lock or unlock calls for, if it's yet to be set and differs from
+    /// `current_mute_state`: muted on lock, and unmuted on unlock if it was unmuted before and
+    /// the settings ask for it. Keeps asking until `applied` is called.
+    pub fn wanted(&mut self, current_mute_state: bool) -> Option<bool> {
+        if !self.pending {
+            return None;
+        }
+
+        let wanted = match self.locked {
+            true => {
+                // Only the first time, a retry after failing to mute sees the same state anyway.
+                self.muted_before.get_or_insert(current_mute_state);
+                Some(true)
+            },
+            false => match self.settings.restore && self.muted_before == Some(false) {
+                true => Some(false),
+                false => None
+            }
+        };
+
+        // Nothing to do when it's that way already, e.g. the button unmuted it while locked.
+        match wanted.filter(|wanted| *wanted != current_mute_state) {
+            Some(wanted) => Some(wanted),
+            None => {
+                self.applied();
+                None
+            }
+        }
+    }
+
+    /// The mute state from `wanted` was set.
+    pub fn applied(&mut self) {
+        self.pending = false;
+        if !self.locked {
+            self.muted_before = None;
+        }
+    }
+}
+
+fn log_failure(bus: &str, connection: zbus::Result<Connection>) -> Option<Connection> {
+    match connection {
+        Ok(connection) => Some(connection),
+        Err(e) => {
+            eprintln!("Could not connect to the {} bus, screen lock won't be noticed through it: {}", bus, e);
+            None
+        }
+    }
+}
+
+/// `LockedHint` changes of the user's graphical session.
+async fn logind_events(connection: &Connection) -> zbus::Result<BoxStream<'static, bool>> {
+    // Running as a user service we aren't in a session, the user's display session is the one.
+    let user: OwnedObjectPath = connection.call_method(
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.login1.Manager"),
+        "GetUser",
+        // SAFETY: getuid has no preconditions and can't fail.
+        &(unsafe { libc::getuid() })
+    ).await?.body()?;
+    let display: OwnedValue = connection.call_method(
+        Some("org.freedesktop.login1"),
+        user.as_str(),
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &("org.freedesktop.login1.User", "Display")
+    ).await?.body()?;
+    let (_, session): (String, OwnedObjectPath) = display.try_into()?;
+
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.freedesktop.login1")?
+        .path(session)?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .arg(0, "org.freedesktop.login1.Session")?
+        .build();
+    let messages = MessageStream::for_match_rule(rule, connection, None).await?;
+
+    Ok(messages
+        .filter_map(|message| async move {
+            let message = message.ok()?;
+            let (_, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) = message.body().ok()?;
+            changed.get("LockedHint").and_then(|locked| bool::try_from(locked.clone()).ok())
+        })
+        .boxed())
+}
+
+/// `ActiveChanged` from whichever screensaver runs (freedesktop, GNOME, MATE, Cinnamon...).
+async fn screensaver_events(connection: &Connection) -> zbus::Result<BoxStream<'static, bool>> {
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .member("ActiveChanged")?
+        .build();
+    let messages = MessageStream::for_match_rule(rule, connection, None).await?;
+
+    Ok(messages
+        .filter_map(|message| async move {
+            let message = message.ok()?;
+            let interface = message.interface()?;
+            match interface.ends_with(".ScreenSaver") {
+                true => message.body::<bool>().ok(),
+                false => None
+            }
+        })
+        .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::UnixStream;
+    use zbus::zvariant::{ObjectPath, Value};
+    use zbus::{ConnectionBuilder, Guid};
+
+    const SESSION_PATH: &str = "/org/freedesktop/login1/session/c1";
+
+    /// Answers as logind would for a user whose graphical session is `SESSION_PATH`. On a private
+    /// connection the object server ignores calls addressed to a bus name, so this replies itself.
+    async fn serve_logind(service: Connection, mut messages: MessageStream) {
+        while let Some(Ok(call)) = messages.next().await {
+            if call.message_type() != MessageType::MethodCall {
+                continue;
+            }
+            let result = match call.member().as_deref() {
+                Some("GetUser") => service.reply(&call, &ObjectPath::try_from("/org/freedesktop/login1/user/_1000").unwrap()).await,
+                Some("Get") => service.reply(&call, &Value::from(("c1", ObjectPath::try_from(SESSION_PATH).unwrap()))).await,
+                _ => continue
+            };
+            result.unwrap();
+        }
+    }
+
+    /// Both ends of a private connection, the fake service's and ours.
+    async fn private_bus() -> (Connection, Connection) {
+        let guid = Guid::generate();
+        let (service, client) = UnixStream::pair().unwrap();
+        tokio::try_join!(
+            ConnectionBuilder::unix_stream(service).server(&guid).p2p().build(),
+            ConnectionBuilder::unix_stream(client).p2p().build()
+        ).unwrap()
+    }
+
+    async fn screensaver(settings: LockSettings) -> (Connection, ScreenLock) {
+        let (service, client) = private_bus().await;
+        (service, ScreenLock::with_connections(settings, None, Some(client)).await)
+    }
+
+    async fn set_active(service: &Connection, interface: &str, active: bool) {
+        service.emit_signal(None::<&str>, "/org/freedesktop/ScreenSaver", interface, "ActiveChanged", &active)
+            .await
+            .unwrap();
+    }
+
+    async fn set_locked_hint(service: &Connection, path: &str, locked: bool) {
+        let mut changed: HashMap<&str, Value> = HashMap::new();
+        changed.insert("LockedHint", Value::Bool(locked));
+        service.emit_signal(
+            None::<&str>,
+            path,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            &("org.freedesktop.login1.Session", changed, Vec::<String>::new())
+        ).await.unwrap();
+    }
+
+    async fn next(lock: &mut ScreenLock) -> bool {
+        tokio::time::timeout(Duration::from_secs(5), lock.next()).await.expect("no lock change")
+    }
+
+    async fn assert_no_change(lock: &mut ScreenLock) {
+        assert!(tokio::time::timeout(Duration::from_millis(200), lock.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn screensaver_locks_and_unlocks() {
+        let (service, mut lock) = screensaver(LockSettings::default()).await;
+
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        assert!(next(&mut lock).await);
+        // Saying it again isn't a change, only the unlock comes through.
+        set_active(&service, "org.gnome.ScreenSaver", true).await;
+        set_active(&service, "org.gnome.ScreenSaver", false).await;
+        assert!(!next(&mut lock).await);
+    }
+
+    #[tokio::test]
+    async fn ignores_active_changed_from_other_interfaces() {
+        let (service, mut lock) = screensaver(LockSettings::default()).await;
+
+        set_active(&service, "org.example.Player", true).await;
+        assert_no_change(&mut lock).await;
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        assert!(next(&mut lock).await);
+    }
+
+    #[tokio::test]
+    async fn follows_logind_locked_hint_for_the_users_session() {
+        let (service, client) = private_bus().await;
+        tokio::spawn(serve_logind(service.clone(), MessageStream::from(&service)));
+        let mut lock = ScreenLock::with_connections(LockSettings::default(), Some(client), None).await;
+
+        // Another session locking isn't ours.
+        set_locked_hint(&service, "/org/freedesktop/login1/session/c2", true).await;
+        assert_no_change(&mut lock).await;
+        set_locked_hint(&service, SESSION_PATH, true).await;
+        assert!(next(&mut lock).await);
+        set_locked_hint(&service, SESSION_PATH, false).await;
+        assert!(!next(&mut lock).await);
+    }
+
+    #[tokio::test]
+    async fn mutes_on_lock_and_restores_unmuted() {
+        let (service, mut lock) = screensaver(LockSettings { restore: true, ..LockSettings::default() }).await;
+
+        assert_eq!(lock.wanted(false), None);
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(false), Some(true));
+        lock.applied();
+        assert_eq!(lock.wanted(true), None);
+
+        set_active(&service, "org.freedesktop.ScreenSaver", false).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(true), Some(false));
+        lock.applied();
+        assert_eq!(lock.wanted(false), None);
+    }
+
+    #[tokio::test]
+    async fn leaves_it_muted_when_it_was_muted_before() {
+        let (service, mut lock) = screensaver(LockSettings { restore: true, ..LockSettings::default() }).await;
+
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(true), None);
+
+        set_active(&service, "org.freedesktop.ScreenSaver", false).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(true), None);
+    }
+
+    #[tokio::test]
+    async fn leaves_it_muted_without_restore() {
+        let (service, mut lock) = screensaver(LockSettings::default()).await;
+
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(false), Some(true));
+        lock.applied();
+
+        set_active(&service, "org.freedesktop.ScreenSaver", false).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(true), None);
+    }
+
+    #[tokio::test]
+    async fn keeps_asking_until_the_mute_is_applied() {
+        let (service, mut lock) = screensaver(LockSettings { restore: true, ..LockSettings::default() }).await;
+
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        next(&mut lock).await;
+        // PulseAudio was away, it's still wanted on the next poll.
+        assert_eq!(lock.wanted(false), Some(true));
+        assert_eq!(lock.wanted(false), Some(true));
+        lock.applied();
+
+        set_active(&service, "org.freedesktop.ScreenSaver", false).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(true), Some(false));
+    }
+
+    #[tokio::test]
+    async fn button_unmuting_while_locked_wins() {
+        let (service, mut lock) = screensaver(LockSettings { restore: true, ..LockSettings::default() }).await;
+
+        set_active(&service, "org.freedesktop.ScreenSaver", true).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(false), Some(true));
+        lock.applied();
+
+        set_active(&service, "org.freedesktop.ScreenSaver", false).await;
+        next(&mut lock).await;
+        assert_eq!(lock.wanted(false), None);
+    }
+}
@@ -1,67 +1,147 @@
 extern crate serialport;
 
 use std::error::Error;
-use std::fmt;
 use clap::Parser;
 use ddaa_protocol::{MessageType, ProtocolMessage};
-use home;
 use mewture_shared;
+use mewture_shared::StartupMute;
 use pulser::simple::PulseAudio;
-use std::fs::read_to_string;
+use std::io::Write;
 use std::path::PathBuf;
-use std::process::exit;
 use std::time::Duration;
-use toml;
-
-use crate::pulseaudio_handler::PulseAudioHandler;
-use crate::serial_handler::SerialHandler;
-
+use tokio::time::MissedTickBehavior;
+
+use crate::call::CallWatcher;
+use crate::desktop::DesktopNotifier;
+use crate::error::MewtureError;
+use crate::feedback::Feedback;
+use crate::hooks::Hooks;
+use crate::lock::ScreenLock;
+use crate::notifier::Notifier;
+use crate::pulseaudio_handler::{PulseAudioHandler, CLIENT_NAME};
+use crate::schedule::{LocalClock, Schedule};
+use crate::shutdown::ShutdownSignal;
+use crate::state::StateFile;
+use crate::status::StatusReporter;
+use crate::transport::{Link, LinkEvent, Transport};
+
+mod call;
+mod check;
+mod desktop;
+mod error;
+mod feedback;
+mod hooks;
+mod hotplug;
+mod lock;
+mod notifier;
 mod serial_handler;
 mod pulseaudio_handler;
-
-/// Mewture Button Host Software
-#[derive(Debug)]
-struct MewtureError(String);
-
-impl fmt::Display for MewtureError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+mod schedule;
+mod shutdown;
+mod state;
+mod status;
+mod tcp_handler;
+mod transport;
+
+/// How often PulseAudio is asked for the source's mute state.
+const AUDIO_POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// How often PulseAudio is asked which apps are recording from the source.
+const CALL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to try reopening the button while it's gone, in case a udev event was missed (or
+/// udev isn't available).
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many transport errors in a row, without a successful read in between, before the button
+/// is closed and left for the reconnect logic to open again, rather than failing over and over.
+const MAX_TRANSPORT_ERRORS: u32 = 5;
+
+/// DDAA variable holding the source's mute state.
+const MUTE_VARIABLE: u8 = 0x00;
+
+/// DDAA variable telling the button whether the host is online (0x01) or going away (0x00).
+const HOST_STATE_VARIABLE: u8 = 0x01;
+
+/// What the loop works with: the source, the button, and the state of the optional features.
+struct Daemon {
+    pulseaudio: PulseAudioHandler,
+    /// The button, while it's connected.
+    port: Option<Transport>,
+    current_mute_state: bool,
+    link: Link,
+    notifier: Notifier,
+    status: StatusReporter,
+    calls: Option<CallWatcher>,
+    desktop: Option<DesktopNotifier>,
+    feedback: Option<Feedback>,
+    hooks: Option<Hooks>,
+    schedule: Option<Schedule>,
+    screen_lock: Option<ScreenLock>,
+    state_file: Option<StateFile>,
+    debug: bool
 }
 
-impl Error for MewtureError {}
-
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
     /// Turn on debug output.
     #[arg(short, long)]
-    debug: bool
+    debug: bool,
+
+    /// Config file to use, instead of `$MEWTURE_CONFIG` or the one in the config directory.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Check the config file and exit, non-zero if there are problems.
+    #[arg(long)]
+    check_config: bool
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Box<dyn Error>> {
     // Parse the command line arguments.
     let cli = Cli::parse();
     // Get the configuration.
-    let filename = match home::home_dir() {
-        Some(path) => {
-            path.join(".mewture/config.toml")
-        },
-        None => {
-            return Err("Failed to get home directory.".into());
-        }
-    };
-    let config: mewture_shared::Config = get_config(filename)?;
+    let filename = mewture_shared::config_path(cli.config.as_deref())?;
+
+    if cli.check_config {
+        let report = check::check_config(&filename);
+        report.print();
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+
+    let config: mewture_shared::Config = get_config(filename.clone())?;
 
     // Print the configuration if debug is enabled.
     if cli.debug {
         println!(
-            "Config:\n    Device name: {:?}\n    Serial port: {:?}\n",
+            "Config:\n    Device name: {:?}\n    Serial port: {:?}\n    Device: {:?}\n    TCP: {:?}\n    PulseAudio: {:?}\n",
             config.audio_device_name,
-            config.serial_port
+            config.serial_port,
+            config.device,
+            config.tcp,
+            config.pulseaudio
         );
     }
 
+    // Talk to a remote PulseAudio server, if one is configured.
+    config.pulseaudio.export();
+
+    // Listen for SIGTERM/SIGINT before doing anything that may wait.
+    let mut shutdown_signal = ShutdownSignal::new()?;
+    // Report our progress to systemd, if it started us.
+    let notifier = Notifier::from_env();
+
+    // Watch for the button being plugged in or removed, or listen for it to connect.
+    let link = Link::from_config(&config).await?;
+
+    // Up and running as far as systemd is concerned: waiting for PulseAudio or the button can
+    // take until someone plugs it in, and shouldn't hold up the rest of the session. How far
+    // we got is in the status instead.
+    notifier.ready();
+    notifier.status(&format!("Waiting for PulseAudio at {}", config.pulseaudio.describe()));
+
     #[warn(unused_assignments)]
     let mut pulseaudio: Option<PulseAudioHandler> = None;
 
@@ -69,8 +149,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Retry initialization every 10 seconds until successful.
     loop {
         match PulseAudioHandler::new(
-            PulseAudio::connect(Some("Mewture Button")),
-            config.audio_device_name.clone()
+            PulseAudio::connect(Some(CLIENT_NAME)),
+            config.audio_device_name.clone(),
+            config.pulseaudio.describe()
         ) {
             Ok(pa) => {
                 pulseaudio = Some(pa);
@@ -81,14 +162,21 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
 
-        // Sleep for 10 seconds before retrying.
-        std::thread::sleep(Duration::from_secs(10));
-    }
+        // Keep the watchdog happy while we wait.
+        notifier.watchdog();
 
-    // Initialization the serial port, with retry.
-    let port: Option<SerialHandler> = init_serial(&config.serial_port, 115200, cli.debug);
+        // Sleep for 10 seconds before retrying, unless we're asked to stop.
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+            _ = shutdown_signal.recv() => {
+                notifier.stopping();
+                return Ok(());
+            }
+        }
+    }
 
     let mut pulseaudio = pulseaudio.unwrap();
+
     // Get the current mute state.
     let mut current_mute_state = pulseaudio.get_mute_state()?;
     if cli.debug {
@@ -96,91 +184,168 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("Initial mute state: {:?}", current_mute_state);
     }
 
-    run(&mut pulseaudio, &mut port.unwrap(), &mut current_mute_state, cli.debug)
+    // Start muted or unmuted if the config says so, or as we were last time, before the button
+    // is told anything.
+    let mut state_file = match config.startup.mute {
+        StartupMute::Restore => match mewture_shared::state_path() {
+            Ok(path) => Some(StateFile::new(path)),
+            Err(e) => {
+                eprintln!("Not keeping the mute state: {}", e);
+                None
+            }
+        },
+        _ => None
+    };
+    let startup_mute_state = match config.startup.mute {
+        StartupMute::Keep => None,
+        StartupMute::Restore => state_file.as_mut().and_then(StateFile::load),
+        StartupMute::Muted => Some(true),
+        StartupMute::Unmuted => Some(false)
+    };
+    if let Some(mute_state) = startup_mute_state.filter(|mute_state| *mute_state != current_mute_state) {
+        eprintln!("{} on startup", if mute_state { "Muting" } else { "Unmuting" });
+        pulseaudio.set_mute_state(mute_state)?;
+        current_mute_state = mute_state;
+    }
+
+    // The loop opens the button once it's there.
+    notifier.status(&format!("Waiting for {}", link.describe()));
+
+    // Only worth listing recording apps when something uses the result.
+    let calls = match config.call.is_some() || config.status.is_some() {
+        true => Some(CallWatcher::new(config.call.clone().unwrap_or_default())),
+        false => None
+    };
+    let desktop = config.notifications.clone().map(|settings| DesktopNotifier::new(settings, None));
+    let feedback = match &config.feedback {
+        Some(settings) => match Feedback::new(settings, &config.audio_device_name).await {
+            Ok(feedback) => Some(feedback),
+            Err(e) => {
+                eprintln!("Feedback sounds are off: {}", e);
+                None
+            }
+        },
+        None => None
+    };
+    let hooks = config.hooks.clone().map(|settings| Hooks::new(settings, config.audio_device_name.clone()));
+    let schedule = Schedule::new(&config.schedule, Box::new(LocalClock))
+        .map_err(|message| MewtureError::Config { path: filename, message })?;
+    let screen_lock = match &config.lock {
+        Some(settings) => Some(ScreenLock::new(settings.clone()).await),
+        None => None
+    };
+    let mut daemon = Daemon {
+        pulseaudio,
+        port: None,
+        current_mute_state,
+        link,
+        notifier,
+        status: StatusReporter::new(config.status.clone()),
+        calls,
+        desktop,
+        feedback,
+        hooks,
+        schedule,
+        screen_lock,
+        state_file,
+        debug: cli.debug
+    };
+    run(&mut daemon, &mut shutdown_signal).await?;
+    shutdown(&mut daemon.pulseaudio, daemon.port.as_mut(), &config, &daemon.notifier, cli.debug).await;
+
+    Ok(())
 }
 
 /// Check if the source's mute state has changed.
-fn check_for_mute_state_change(
+/// The new state is recorded whether or not the button could be told, it's synced again when
+/// it (re)connects, so a button that keeps failing doesn't repeat the notification or the sound.
+/// Changes made by the button show up here too, so this is where the desktop notification and
+/// the feedback sound come from.
+async fn check_for_mute_state_change(
     pulseaudio: &mut PulseAudioHandler,
-    port: &mut SerialHandler,
+    port: Option<&mut Transport>,
     current_mute_state: &mut bool,
+    desktop: &mut Option<DesktopNotifier>,
+    feedback: &Option<Feedback>,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
     // Check if the source mute state has changed.
     let new_mute_state = pulseaudio.get_mute_state()?;
+    if new_mute_state == *current_mute_state {
+        return Ok(());
+    }
 
-    if new_mute_state != *current_mute_state {
-        if debug {
-            println!("Mute state changed from {:?} to {:?}", current_mute_state, new_mute_state);
-        }
+    if debug {
+        println!("Mute state changed from {:?} to {:?}", current_mute_state, new_mute_state);
+    }
+    *current_mute_state = new_mute_state;
 
-        let mut message = ProtocolMessage {
-            message_type: MessageType::Request,
-            command: ddaa_protocol::Command::Write,
-            variable: 0x00,
-            data: vec![0x00]
-        };
+    if let Some(feedback) = feedback {
+        feedback.mute_changed(new_mute_state);
+    }
+    if let Some(desktop) = desktop {
+        desktop.mute_changed(new_mute_state, &pulseaudio.description()).await;
+    }
 
-        message.data[0] = u8::from(new_mute_state);
-        return match write_message_to_port(
-            port,
-            MessageType::Request,
-            message,
-            debug
-        ) {
-            Ok(_) => {
-                if debug {
-                    println!("Setting mute state variable to {:?}", new_mute_state);
-                }
+    let port = match port {
+        Some(port) => port,
+        None => return Ok(())
+    };
 
-                *current_mute_state = new_mute_state;
-                Ok(())
-            },
-            Err(e) => {
-                eprintln!("Error writing to serial port: {}", e);
-                Err(e.into())
+    match set_mute_state(port, new_mute_state, debug).await {
+        Ok(_) => {
+            if debug {
+                println!("Setting mute state variable to {:?}", new_mute_state);
             }
-        };
-    }
 
-    Ok(())
+            Ok(())
+        },
+        Err(e) => {
+            eprintln!("Error writing to the button: {}", e);
+            Err(e)
+        }
+    }
 }
 
-/// Get the configuration.
-fn get_config(path: PathBuf) -> Result<mewture_shared::Config, Box<dyn Error>> {
-    let content = match read_to_string(&path) {
-        Ok(c) => c,
-        Err(_) => {
-            return Err(format!("Could not read file `{}`", path.display()).into());
-        }
+/// Get the configuration, upgrading the file if it was written by an older version.
+fn get_config(path: PathBuf) -> Result<mewture_shared::Config, MewtureError> {
+    let config = match mewture_shared::load_config(&path) {
+        Ok(config) => config,
+        Err(message) => return Err(MewtureError::Config { path, message })
     };
 
-    match toml::from_str(&content) {
-        Ok(c) => Ok(c),
-        Err(e) =>
-            Err(
-                format!(
-                    "Unable to load data from `{}`. Error: {:?}",
-                    path.display(),
-                    e
-                ).into()
-            )
+    // The config was upgraded in memory already, so failing to write it back isn't fatal.
+    // There may be no file of our own when running off the system config alone.
+    if !path.exists() {
+        return Ok(config);
     }
+    match mewture_shared::migrate_config(&path) {
+        Ok(Some(backup)) => eprintln!(
+            "Migrated {} to config version {}, the old file was saved as {}",
+            path.display(),
+            mewture_shared::CONFIG_VERSION,
+            backup.display()
+        ),
+        Ok(None) => {},
+        Err(e) => eprintln!("Could not migrate {}: {}", path.display(), e)
+    }
+
+    Ok(config)
 }
 
 /// Handle a read request.
-fn handle_read_request(
-    port: &mut SerialHandler,
+async fn handle_read_request(
+    port: &mut Transport,
     parsed_message: ProtocolMessage,
     current_mute_state: &bool,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
     // Received read request.
     if debug {
         println!("Received read request");
     }
 
-    if parsed_message.variable == 0x00 {
+    if parsed_message.variable == MUTE_VARIABLE {
         // Respond with current mute state.
         if debug {
             println!("Received mute read request");
@@ -193,15 +358,16 @@ fn handle_read_request(
             &[u8::from(*current_mute_state)]
         );
 
-        match port.write(&buffer) {
+        match port.write(&buffer).await {
             Ok(size) => {
                 if debug {
                     println!("Wrote {} bytes", size);
                     println!("Wrote: {:?}", buffer);
                 }
             }
-            Err(_e) => {
-                eprintln!("Error writing to serial port");
+            Err(e) => {
+                eprintln!("Error writing to the button: {}", e);
+                return Err(e);
             }
         }
     }
@@ -211,13 +377,15 @@ fn handle_read_request(
 }
 
 /// Handle a request.
-fn handle_request(
+async fn handle_request(
     pulseaudio: &mut PulseAudioHandler,
-    port: &mut SerialHandler,
+    port: &mut Transport,
     parsed_message: ProtocolMessage,
     current_mute_state: &mut bool,
+    status: &mut StatusReporter,
+    schedule: &mut Option<Schedule>,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
     if debug {
         println!("Received request: {:?}", parsed_message);
     }
@@ -229,15 +397,15 @@ fn handle_request(
                 println!("Received ping");
             }
 
-            respond_to_ping(port, parsed_message);
+            respond_to_ping(port, parsed_message).await?
         }
         ddaa_protocol::Command::Read => {
             // Received read request.
-            handle_read_request(port, parsed_message, current_mute_state, debug)?
+            handle_read_request(port, parsed_message, current_mute_state, debug).await?
         }
         ddaa_protocol::Command::Write => {
             // Received write request.
-            handle_write_request(pulseaudio, port, parsed_message, current_mute_state, debug)?
+            handle_write_request(pulseaudio, port, parsed_message, current_mute_state, status, schedule, debug).await?
         }
     }
 
@@ -245,25 +413,17 @@ fn handle_request(
 }
 
 /// Handle incoming serial data.
-fn handle_serial_data(
-    port: &mut SerialHandler,
+#[allow(clippy::too_many_arguments)]
+async fn handle_serial_data(
+    port: &mut Transport,
     pulseaudio: &mut PulseAudioHandler,
     received_buffer: &mut [u8],
+    bytes_read: usize,
     current_mute_state: &mut bool,
+    status: &mut StatusReporter,
+    schedule: &mut Option<Schedule>,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
-    let bytes_read = match port.read(received_buffer) {
-        Ok(bytes_read) => bytes_read,
-        Err(e) => {
-            let error = e.downcast::<std::io::Error>().unwrap().to_string();
-            if error == "Broken pipe" {
-                return Err(Box::new(MewtureError("Disconnected".into())));
-            }
-            // Else, set 0.
-            0
-        }
-    };
-
+) -> Result<(), MewtureError> {
     if bytes_read > 7 {
         // Parse the received data.
         let message = ddaa_protocol::parse_protocol_message(received_buffer.to_vec().as_mut());
@@ -283,8 +443,10 @@ fn handle_serial_data(
                     port,
                     parsed_message,
                     current_mute_state,
+                    status,
+                    schedule,
                     debug
-                )?
+                ).await?
             }
         }
     } else if bytes_read > 0 && bytes_read <= 7 {
@@ -295,23 +457,41 @@ fn handle_serial_data(
 }
 
 /// Handle a write request.
-fn handle_write_request(
+async fn handle_write_request(
     pulseaudio: &mut PulseAudioHandler,
-    port: &mut SerialHandler,
+    port: &mut Transport,
     parsed_message: ProtocolMessage,
     current_mute_state: &mut bool,
+    status: &mut StatusReporter,
+    schedule: &mut Option<Schedule>,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
     if debug {
         println!("Received write request: {:?}", parsed_message);
     }
 
-    if parsed_message.variable == 0x00 {
+    if parsed_message.variable == MUTE_VARIABLE {
         if debug {
             println!("Received mute write request");
         }
 
-        match parsed_message.data[0] {
+        let value = match parsed_message.data.first() {
+            Some(value) => *value,
+            None => {
+                return Err(MewtureError::Protocol {
+                    message: "Received mute write request without a value".to_string()
+                });
+            }
+        };
+
+        // Whoever pressed the button wants it their way, the schedule waits for its next change.
+        if value <= 0x04 {
+            if let Some(schedule) = schedule {
+                schedule.button_pressed();
+            }
+        }
+
+        match value {
             0x00 => {
                 // Received mute request.
                 // Set source mute state to false.
@@ -320,7 +500,7 @@ fn handle_write_request(
                 }
 
                 pulseaudio.set_mute_state(false)?;
-                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug)?;
+                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug).await?;
             }
             0x01 => {
                 // Received unmute request.
@@ -330,7 +510,7 @@ fn handle_write_request(
                 }
 
                 pulseaudio.set_mute_state(true)?;
-                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug)?;
+                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug).await?;
             }
             0x02 => {
                 // Received invert mute request.
@@ -339,13 +519,33 @@ fn handle_write_request(
                 }
 
                 pulseaudio.set_mute_state(!*current_mute_state)?;
-                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug)?;
+                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug).await?;
 
             }
+            0x03 => {
+                // Received push-to-talk pressed, unmute while it's held.
+                if debug {
+                    println!("Received push-to-talk pressed request");
+                }
+
+                pulseaudio.set_mute_state(false)?;
+                status.push_to_talk = true;
+                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug).await?;
+            }
+            0x04 => {
+                // Received push-to-talk released, mute again.
+                if debug {
+                    println!("Received push-to-talk released request");
+                }
+
+                pulseaudio.set_mute_state(true)?;
+                status.push_to_talk = false;
+                write_message_to_port(port, MessageType::ResponseSuccess, parsed_message, debug).await?;
+            }
             _ => {
                 // Data for mute variable is invalid.
-                eprintln!("Received unknown value: {:?}", parsed_message.data[0]);
-                write_message_to_port(port, MessageType::ResponseError, parsed_message, debug)?;
+                eprintln!("Received unknown value: {:?}", value);
+                write_message_to_port(port, MessageType::ResponseError, parsed_message, debug).await?;
             }
         }
     }
@@ -353,79 +553,279 @@ fn handle_write_request(
     Ok(())
 }
 
-/// Initialize serial port (or re-initialize if there was a disconnect).
-fn init_serial(port_path: &str, baud_rate: u32, debug: bool) -> Option<SerialHandler> {
-    let mut port: Option<SerialHandler> = None;
+/// Open the button at `target` and bring it up to date.
+async fn connect(
+    link: &mut Link,
+    target: &str,
+    current_mute_state: bool,
+    notifier: &Notifier,
+    debug: bool
+) -> Result<Transport, MewtureError> {
+    let port = link.open(target).await?;
+    bring_up(port, current_mute_state, notifier, debug).await
+}
 
-    loop {
-        match SerialHandler::new(port_path, baud_rate) {
-            Ok(sp) => {
-                port = Some(sp);
-                break; // Initialization successful, exit the loop.
-            }
-            Err(e) => {
-                if debug {
-                    eprintln!("Error initializing SerialHandler: {}", e);
-                }
+/// Bring a freshly connected button up to date: tell it we're here and what the mute state is.
+async fn bring_up(
+    mut port: Transport,
+    current_mute_state: bool,
+    notifier: &Notifier,
+    debug: bool
+) -> Result<Transport, MewtureError> {
+    set_host_state(&mut port, true, debug).await?;
+    set_mute_state(&mut port, current_mute_state, debug).await?;
+
+    if debug {
+        println!("Connected to {}", port.name());
+    }
+
+    notifier.status(&format!("Connected to {}", port.name()));
+    Ok(port)
+}
+
+/// Read from the button, if it's connected. Waits forever otherwise.
+async fn read_port(port: &mut Option<Transport>, buffer: &mut [u8]) -> Result<usize, MewtureError> {
+    match port {
+        Some(port) => port.read(buffer).await,
+        None => std::future::pending().await
+    }
+}
+
+/// Try to reconnect to the button at `target`. `port` stays empty if it isn't there (yet).
+async fn reconnect(
+    port: &mut Option<Transport>,
+    target: &str,
+    link: &mut Link,
+    current_mute_state: bool,
+    notifier: &Notifier,
+    debug: bool
+) -> Result<(), MewtureError> {
+    match connect(link, target, current_mute_state, notifier, debug).await {
+        Ok(sp) => {
+            *port = Some(sp);
+        },
+        Err(e) => {
+            if debug {
+                eprintln!("Error reconnecting to {}: {}", target, e);
             }
         }
-
-        // Sleep for 10 seconds before retrying.
-        std::thread::sleep(Duration::from_secs(10));
     }
 
-    return port;
+    Ok(())
 }
 
 /// Respond to a ping message.
-fn respond_to_ping(port: &mut SerialHandler, message: ProtocolMessage) {
+async fn respond_to_ping(port: &mut Transport, message: ProtocolMessage) -> Result<(), MewtureError> {
     match port.write(&ddaa_protocol::create_protocol_buffer(
         MessageType::ResponseSuccess,
         ddaa_protocol::Command::Ping,
         message.variable,
         &message.data,
-    )) {
-        Ok(_) => { },
+    )).await {
+        Ok(_) => Ok(()),
         Err(e) => {
-            // Something bad happened, let's just exit with an error.
-            eprintln!("Error writing to serial port: {}", e);
-            exit(1);
+            eprintln!("Error writing to the button: {}", e);
+            Err(e)
         }
-    };
+    }
 }
 
-/// The loop that does all the things for the daemon.
-fn run(
-    pulseaudio: &mut PulseAudioHandler,
-    port: &mut SerialHandler,
-    current_mute_state: &mut bool,
+/// Decide what to do with the outcome of one step of the loop.
+/// Drops the port on a disconnect (the loop takes care of reconnecting), logs other retryable
+/// errors and hands fatal ones back. A port that keeps failing (`transport_errors` counts it) is
+/// dropped too, so it's opened again after a while instead of being hammered.
+fn recover(
+    port: &mut Option<Transport>,
+    result: Result<(), MewtureError>,
+    transport_errors: &mut u32,
+    link: &Link,
+    notifier: &Notifier,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(MewtureError::Disconnected { port: port_path }) => {
+            if debug {
+                eprintln!("Disconnected: {:?}", port_path);
+            }
+
+            *port = None;
+            *transport_errors = 0;
+            notifier.status(&format!("Waiting for {}", link.describe()));
+            Ok(())
+        },
+        Err(e @ MewtureError::Transport { .. }) if e.is_retryable() && port.is_some() => {
+            eprintln!("{}", e);
+            *transport_errors += 1;
+            if *transport_errors >= MAX_TRANSPORT_ERRORS {
+                eprintln!("{} errors in a row, closing {} to open it again", transport_errors, link.describe());
+                *port = None;
+                *transport_errors = 0;
+                notifier.status(&format!("Waiting for {}", link.describe()));
+            }
+            Ok(())
+        },
+        Err(e) if e.is_retryable() => {
+            eprintln!("{}", e);
+            Ok(())
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// The loop that does all the things for the daemon.
+/// Waits on whichever comes first, data from the button, the next audio or call poll, the
+/// button coming or going, the screen locking or unlocking, or a request to stop, and handles
+/// it without holding up the others.
+/// Returns `Ok` once a shutdown was requested.
+async fn run(daemon: &mut Daemon, shutdown_signal: &mut ShutdownSignal) -> Result<(), MewtureError> {
+    let Daemon {
+        pulseaudio,
+        port,
+        current_mute_state,
+        link,
+        notifier,
+        status,
+        calls,
+        desktop,
+        feedback,
+        hooks,
+        schedule,
+        screen_lock,
+        state_file,
+        debug
+    } = daemon;
+    let debug = *debug;
     let mut received_buffer: Vec<u8> = vec![0; 64];
+    // Transport errors since the last successful read, see `recover`.
+    let mut transport_errors: u32 = 0;
+    // PulseAudio is polled, so check the mute state on a fixed interval.
+    let mut audio_poll = tokio::time::interval(AUDIO_POLL_INTERVAL);
+    audio_poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // Recording apps are polled too, less often.
+    let mut call_poll = calls.as_ref().map(|_| tokio::time::interval(CALL_POLL_INTERVAL));
+    // Fallback for reconnecting, when udev didn't tell us about the button coming back.
+    let mut reconnect_poll = tokio::time::interval(RECONNECT_INTERVAL);
+    reconnect_poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // Ping the systemd watchdog, if it's enabled.
+    let mut watchdog = notifier.watchdog_interval().map(tokio::time::interval);
+
     loop {
-        // Handle incoming serial data.
-        let s_result = match handle_serial_data(port, pulseaudio, &mut received_buffer, current_mute_state, debug) {
-            Ok(_) => { 0 },
-            Err(e) => {
-                let error = e.to_string();
-                match error {
-                    _ if error == "Disconnected" => { 1 },
-                    _ => panic!("Unknown serial error.")
+        let connected = port.is_some();
+        let result = tokio::select! {
+            read = read_port(port, &mut received_buffer) => {
+                // Handle incoming serial data.
+                match (read, port.as_mut()) {
+                    (Ok(bytes_read), Some(sp)) => {
+                        transport_errors = 0;
+                        handle_serial_data(
+                            sp,
+                            pulseaudio,
+                            &mut received_buffer,
+                            bytes_read,
+                            current_mute_state,
+                            status,
+                            schedule,
+                            debug
+                        ).await
+                    },
+                    (Ok(_), None) => Ok(()),
+                    (Err(e), _) => Err(e)
                 }
             }
-        };
+            _ = audio_poll.tick() => {
+                // Check if the source mute state has changed, once the server is back if it went away.
+                // Then see whether the schedule wants it changed, the button follows on the next poll.
+                match pulseaudio.ensure_connected() {
+                    // A lock or unlock that came while PulseAudio was away is applied now, and the
+                    // schedule waits while the screen is locked.
+                    true => match check_for_mute_state_change(pulseaudio, port.as_mut(), current_mute_state, desktop, feedback, debug).await {
+                        Ok(_) => match apply_screen_lock(pulseaudio, screen_lock, state_file, *current_mute_state) {
+                            Ok(_) if status.locked => Ok(()),
+                            Ok(_) => apply_schedule(pulseaudio, schedule, state_file, *current_mute_state),
+                            Err(e) => Err(e)
+                        },
+                        Err(e) => Err(e)
+                    },
+                    false => Ok(())
+                }
+            }
+            _ = tick(&mut call_poll) => {
+                match calls {
+                    Some(calls) if pulseaudio.ensure_connected() => {
+                        let result = match calls.check(pulseaudio, *current_mute_state, status.locked, debug) {
+                            Ok(Some(wanted)) => set_mute_automatically(pulseaudio, state_file, wanted),
+                            Ok(None) => Ok(()),
+                            Err(e) => Err(e)
+                        };
+                        status.in_call = calls.in_call();
+                        result
+                    },
+                    _ => Ok(())
+                }
+            }
+            locked = next_lock(screen_lock) => {
+                // Without PulseAudio it's left for the audio poll, once the server is back.
+                status.locked = locked;
+                match pulseaudio.ensure_connected() {
+                    true => apply_screen_lock(pulseaudio, screen_lock, state_file, *current_mute_state),
+                    false => Ok(())
+                }
+            }
+            event = link.next(connected) => {
+                match event {
+                    Ok(LinkEvent::Added(target)) if port.is_none() => {
+                        reconnect(port, &target, link, *current_mute_state, notifier, debug).await
+                    },
+                    Ok(LinkEvent::Removed) if port.is_some() => {
+                        Err(MewtureError::Disconnected { port: link.describe() })
+                    },
+                    Ok(LinkEvent::Connected(connected)) => {
+                        // A button connecting again means the old connection is dead, even if
+                        // we haven't noticed yet.
+                        match bring_up(*connected, *current_mute_state, notifier, debug).await {
+                            Ok(connected) => {
+                                *port = Some(connected);
+                                status.reset();
+                                Ok(())
+                            },
+                            Err(e) => Err(e)
+                        }
+                    },
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e)
+                }
+            }
+            _ = reconnect_poll.tick(), if port.is_none() => {
+                match link.resolve() {
+                    Some(target) => reconnect(port, &target, link, *current_mute_state, notifier, debug).await,
+                    None => Ok(())
+                }
+            }
+            _ = tick(&mut watchdog) => {
+                notifier.watchdog();
+                Ok(())
+            }
+            _ = shutdown_signal.recv() => {
+                if debug {
+                    println!("Shutdown requested");
+                }
 
-        if s_result == 1 {
-            if debug {
-                eprintln!("Disconnected: {:?}", port.get_name().unwrap());
+                return Ok(());
             }
+        };
 
-            let port_path: &str = &port.get_name().unwrap()[..];
-            *port = init_serial(port_path, 115200, debug).unwrap();
-        }
+        recover(port, result, &mut transport_errors, link, notifier, debug)?;
 
-        // Check if the source mute state has changed.
-        check_for_mute_state_change(pulseaudio, port, current_mute_state, debug)?;
+        // Keep the button's status in step with whatever just happened.
+        let result = status.update(port.as_mut(), pulseaudio.health(), *current_mute_state, debug).await;
+        recover(port, result, &mut transport_errors, link, notifier, debug)?;
+        if let Some(hooks) = hooks {
+            hooks.update(port.as_ref().map(Transport::name), *current_mute_state, pulseaudio.health());
+        }
+        if let Some(state_file) = state_file {
+            state_file.update(*current_mute_state);
+        }
 
         // Clear the buffer.
         received_buffer.clear();
@@ -433,20 +833,144 @@ fn run(
     }
 }
 
+/// Mute or unmute the source if the schedule says so.
+fn apply_schedule(
+    pulseaudio: &mut PulseAudioHandler,
+    schedule: &mut Option<Schedule>,
+    state_file: &mut Option<StateFile>,
+    current_mute_state: bool
+) -> Result<(), MewtureError> {
+    let wanted = match schedule.as_mut().and_then(|schedule| schedule.wanted(current_mute_state)) {
+        Some(wanted) => wanted,
+        None => return Ok(())
+    };
+
+    eprintln!("{} on schedule", if wanted { "Muting" } else { "Unmuting" });
+    set_mute_automatically(pulseaudio, state_file, wanted)
+}
+
+/// Mute or unmute for the last lock or unlock of the screen, if that's yet to be done.
+fn apply_screen_lock(
+    pulseaudio: &mut PulseAudioHandler,
+    screen_lock: &mut Option<ScreenLock>,
+    state_file: &mut Option<StateFile>,
+    current_mute_state: bool
+) -> Result<(), MewtureError> {
+    let screen_lock = match screen_lock {
+        Some(screen_lock) => screen_lock,
+        None => return Ok(())
+    };
+    let wanted = match screen_lock.wanted(current_mute_state) {
+        Some(wanted) => wanted,
+        None => return Ok(())
+    };
+
+    eprintln!("{} for the screen lock", if wanted { "Muting" } else { "Unmuting" });
+    set_mute_automatically(pulseaudio, state_file, wanted)?;
+    screen_lock.applied();
+    Ok(())
+}
+
+/// Mute or unmute on the daemon's own account, which isn't kept as the user's choice.
+fn set_mute_automatically(
+    pulseaudio: &mut PulseAudioHandler,
+    state_file: &mut Option<StateFile>,
+    muted: bool
+) -> Result<(), MewtureError> {
+    pulseaudio.set_mute_state(muted)?;
+    if let Some(state_file) = state_file {
+        state_file.set_by_daemon(muted);
+    }
+    Ok(())
+}
+
+/// Tell the button what the source's mute state is.
+async fn set_mute_state(port: &mut Transport, mute_state: bool, debug: bool) -> Result<(), MewtureError> {
+    let message = ProtocolMessage {
+        message_type: MessageType::Request,
+        command: ddaa_protocol::Command::Write,
+        variable: MUTE_VARIABLE,
+        data: vec![u8::from(mute_state)]
+    };
+
+    write_message_to_port(port, MessageType::Request, message, debug).await
+}
+
+/// Tell the button whether the host is online, so it can show when the daemon goes away.
+async fn set_host_state(port: &mut Transport, online: bool, debug: bool) -> Result<(), MewtureError> {
+    let message = ProtocolMessage {
+        message_type: MessageType::Request,
+        command: ddaa_protocol::Command::Write,
+        variable: HOST_STATE_VARIABLE,
+        data: vec![u8::from(online)]
+    };
+
+    write_message_to_port(port, MessageType::Request, message, debug).await
+}
+
+/// Clean up before exiting: let the button know we're going away, put back the default source
+/// if asked to and flush the logs. Errors are only reported, since we're exiting anyway.
+async fn shutdown(
+    pulseaudio: &mut PulseAudioHandler,
+    port: Option<&mut Transport>,
+    config: &mewture_shared::Config,
+    notifier: &Notifier,
+    debug: bool
+) {
+    notifier.stopping();
+
+    if let Some(port) = port {
+        if let Err(e) = set_host_state(port, false, debug).await {
+            eprintln!("Error setting host state: {}", e);
+        }
+    }
+
+    if config.restore_default_source {
+        if let Err(e) = pulseaudio.restore_default_source() {
+            eprintln!("Error restoring default source: {}", e);
+        }
+    }
+
+    if debug {
+        println!("Exiting");
+    }
+
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+}
+
+/// Waits for the screen to lock or unlock, never returns when it isn't watched.
+async fn next_lock(screen_lock: &mut Option<ScreenLock>) -> bool {
+    match screen_lock {
+        Some(screen_lock) => screen_lock.next().await,
+        None => std::future::pending().await
+    }
+}
+
+/// Wait for the next tick of an optional interval, forever if there's none.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => std::future::pending().await
+    }
+}
+
 /// Write a message to the serial port.
-fn write_message_to_port(
-    port: &mut SerialHandler,
+async fn write_message_to_port(
+    port: &mut Transport,
     message_type: MessageType,
     parsed_message: ProtocolMessage,
     debug: bool
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), MewtureError> {
     let buffer = &ddaa_protocol::create_protocol_buffer(
         message_type,
         parsed_message.command,
         parsed_message.variable,
         &parsed_message.data
     );
-    match port.write(buffer) {
+    match port.write(buffer).await {
         Ok(size) => {
             if debug {
                 println!("write_response_to_port responded with {:?} bytes", size);
@@ -456,8 +980,8 @@ fn write_message_to_port(
             Ok(())
         }
         Err(e) => {
-            eprintln!("Error writing to serial port: {}", e);
-            Err(e.into())
+            eprintln!("Error writing to the button: {}", e);
+            Err(e)
         }
     }
 }
@@ -0,0 +1,182 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Talks to systemd through the `sd_notify` protocol: readiness, status strings and watchdog pings.
+/// When the daemon isn't started by systemd (no `NOTIFY_SOCKET`), every call does nothing.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog_interval: Option<Duration>
+}
+
+impl Notifier {
+    /// Creates a notifier from the environment systemd hands to the service:
+    /// `NOTIFY_SOCKET` for where to send messages to, and `WATCHDOG_USEC`/`WATCHDOG_PID` for
+    /// whether and how often the watchdog wants to be pinged.
+    pub fn from_env() -> Self {
+        let socket = match env::var("NOTIFY_SOCKET") {
+            Ok(path) => Self::connect(&path),
+            Err(_) => None
+        };
+
+        // The watchdog is meant for us only if it doesn't name another process.
+        let for_us = match env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+            Err(_) => true
+        };
+        let watchdog_interval = match env::var("WATCHDOG_USEC") {
+            // Ping twice per period, as recommended by sd_watchdog_enabled(3).
+            Ok(usec) if for_us => usec.parse::<u64>().ok()
+                .filter(|usec| *usec > 0)
+                .map(|usec| Duration::from_micros(usec / 2)),
+            _ => None
+        };
+
+        Self { socket, watchdog_interval }
+    }
+
+    /// Opens an unbound datagram socket and resolves the address to send to.
+    /// Paths starting with `@` are in the abstract namespace.
+    fn connect(path: &str) -> Option<(UnixDatagram, SocketAddr)> {
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(path)
+        };
+
+        match (UnixDatagram::unbound(), address) {
+            (Ok(socket), Ok(address)) => Some((socket, address)),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Error connecting to NOTIFY_SOCKET `{}`: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// How often `watchdog` should be called, if the watchdog is enabled.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    /// Tells systemd that start-up is finished.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Sets the free-form status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Pings the watchdog.
+    pub fn watchdog(&self) {
+        if self.watchdog_interval.is_some() {
+            self.notify("WATCHDOG=1");
+        }
+    }
+
+    /// Tells systemd that we're shutting down.
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Sends a raw notification. Failures are only reported, they should never stop the daemon.
+    pub fn notify(&self, state: &str) {
+        if let Some((socket, address)) = &self.socket {
+            if let Err(e) = socket.send_to_addr(state.as_bytes(), address) {
+                eprintln!("Error notifying systemd: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test thread.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn from_env(vars: &[(&str, &str)]) -> Notifier {
+        for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        Notifier::from_env()
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0u8; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn sends_to_a_socket_path() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let notifier = from_env(&[("NOTIFY_SOCKET", path.to_str().unwrap())]);
+        notifier.ready();
+        notifier.status("Connected");
+        notifier.stopping();
+
+        assert_eq!(receive(&systemd), "READY=1");
+        assert_eq!(receive(&systemd), "STATUS=Connected");
+        assert_eq!(receive(&systemd), "STOPPING=1");
+    }
+
+    #[test]
+    fn sends_to_an_abstract_socket() {
+        let _env = ENV.lock().unwrap();
+        let name = format!("mewture-notify-test-{}", std::process::id());
+        let systemd = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        systemd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        from_env(&[("NOTIFY_SOCKET", &format!("@{}", name))]).ready();
+
+        assert_eq!(receive(&systemd), "READY=1");
+    }
+
+    #[test]
+    fn does_nothing_without_a_socket() {
+        let _env = ENV.lock().unwrap();
+        let notifier = from_env(&[]);
+        assert!(notifier.socket.is_none());
+        notifier.ready();
+    }
+
+    #[test]
+    fn pings_the_watchdog_twice_per_period() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let notifier = from_env(&[("NOTIFY_SOCKET", path.to_str().unwrap()), ("WATCHDOG_USEC", "20000000")]);
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(10)));
+        notifier.watchdog();
+        assert_eq!(receive(&systemd), "WATCHDOG=1");
+    }
+
+    #[test]
+    fn reads_the_watchdog_settings() {
+        let _env = ENV.lock().unwrap();
+        let pid = std::process::id().to_string();
+        let ours = from_env(&[("WATCHDOG_USEC", "1000"), ("WATCHDOG_PID", &pid)]);
+        assert_eq!(ours.watchdog_interval(), Some(Duration::from_micros(500)));
+
+        let other = (std::process::id() + 1).to_string();
+        assert_eq!(from_env(&[("WATCHDOG_USEC", "1000"), ("WATCHDOG_PID", &other)]).watchdog_interval(), None);
+        assert_eq!(from_env(&[("WATCHDOG_USEC", "0")]).watchdog_interval(), None);
+        assert_eq!(from_env(&[("WATCHDOG_USEC", "soon")]).watchdog_interval(), None);
+        assert_eq!(from_env(&[]).watchdog_interval(), None);
+    }
+}
@@ -1,10 +1,39 @@
-use std::error::Error;
 use pulser::api::PAIdent;
 use pulser::simple::PulseAudio;
+use std::time::{Duration, Instant};
+
+use crate::error::MewtureError;
+
+/// Name the daemon shows up as in PulseAudio.
+pub const CLIENT_NAME: &str = "Mewture Button";
+
+/// First wait before reconnecting to a server that went away, doubled on every failed attempt.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnection attempts.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// How the last request to the server went.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioHealth {
+    Connected,
+    /// The server is there, the managed source isn't.
+    NoDevice,
+    /// The server can't be reached.
+    ServerDown
+}
 
 pub struct PulseAudioHandler {
-    device_name: PAIdent,
-    pulseaudio: PulseAudio
+    device_name: String,
+    original_default_source: Option<String>,
+    pulseaudio: PulseAudio,
+    /// Where the server is, for messages.
+    server: String,
+    health: AudioHealth,
+    /// When to try reconnecting next, while not connected.
+    next_attempt: Instant,
+    /// How long to wait after the next failed attempt.
+    backoff: Duration
 }
 
 // Abstract the pulser crate for future testing?
@@ -15,14 +44,93 @@ impl PulseAudioHandler {
     ///
     /// * `pulseaudio` - A `PulseAudio` instance.
     /// * `device_name` - The name of the audio device to manage.
+    /// * `server` - Where `pulseaudio` is connected to, for messages.
     ///
     /// # Errors
     ///
     /// Returns an error if setting the default source fails.
-    pub fn new(pulseaudio: PulseAudio, device_name: String) -> Result<Self, Box<dyn Error>> {
-        let device_name = PAIdent::Name(device_name);
-        pulseaudio.set_default_source(device_name.clone())?;
-        Ok(Self { device_name, pulseaudio })
+    pub fn new(pulseaudio: PulseAudio, device_name: String, server: String) -> Result<Self, MewtureError> {
+        // Remember what the default source was, so it can be put back on exit.
+        let original_default_source = match pulseaudio.get_server_info() {
+            Ok(info) => info.default_source_name,
+            Err(_) => None
+        };
+
+        pulseaudio.set_default_source(PAIdent::Name(device_name.clone()))
+            .map_err(|e| MewtureError::audio(&device_name, e))?;
+        Ok(Self {
+            device_name,
+            original_default_source,
+            pulseaudio,
+            server,
+            health: AudioHealth::Connected,
+            next_attempt: Instant::now(),
+            backoff: RECONNECT_BACKOFF
+        })
+    }
+
+    /// Makes sure there's a working connection to the server, reconnecting if the last request
+    /// failed and it's time for another attempt. Attempts back off up to `RECONNECT_BACKOFF_MAX`
+    /// while the server stays away (a remote server on a flaky network, or a restarting one).
+    /// Returns whether the server can be used.
+    pub fn ensure_connected(&mut self) -> bool {
+        if self.health == AudioHealth::Connected {
+            return true;
+        }
+        if Instant::now() < self.next_attempt {
+            return false;
+        }
+
+        match self.reconnect() {
+            Ok(_) => {
+                eprintln!("Reconnected to PulseAudio at {}", self.server);
+                self.backoff = RECONNECT_BACKOFF;
+                true
+            },
+            Err(e) => {
+                eprintln!("Could not reconnect to PulseAudio at {}, retrying in {:?}: {}", self.server, self.backoff, e);
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                false
+            }
+        }
+    }
+
+    /// Opens a new connection, and makes the managed source the default on it again.
+    fn reconnect(&mut self) -> Result<(), MewtureError> {
+        let pulseaudio = PulseAudio::connect(Some(CLIENT_NAME));
+        if let Err(e) = pulseaudio.get_server_info() {
+            self.health = AudioHealth::ServerDown;
+            return Err(MewtureError::audio(&self.server, e));
+        }
+
+        self.pulseaudio = pulseaudio;
+        if let Err(e) = self.pulseaudio.set_default_source(PAIdent::Name(self.device_name.clone())) {
+            self.health = AudioHealth::NoDevice;
+            return Err(MewtureError::audio(&self.device_name, e));
+        }
+
+        self.health = AudioHealth::Connected;
+        Ok(())
+    }
+
+    /// Notes that a request failed, so the next `ensure_connected` reconnects.
+    /// The error can't tell a dropped connection from a missing source, so the server is asked
+    /// whether it's still there. Either way a new connection doesn't hurt.
+    fn failed(&mut self, error: MewtureError) -> MewtureError {
+        if self.health == AudioHealth::Connected {
+            self.health = match self.pulseaudio.get_server_info() {
+                Ok(_) => AudioHealth::NoDevice,
+                Err(_) => AudioHealth::ServerDown
+            };
+            eprintln!("Lost PulseAudio at {} ({:?}), reconnecting", self.server, self.health);
+            self.next_attempt = Instant::now();
+        }
+        error
+    }
+
+    pub fn health(&self) -> AudioHealth {
+        self.health
     }
 
     /// Gets the mute state of the managed audio source.
@@ -30,9 +138,10 @@ impl PulseAudioHandler {
     /// # Errors
     ///
     /// Returns an error if getting the mute state fails.
-    pub fn get_mute_state(&mut self) -> Result<bool, Box<dyn Error>> {
-        let mute_state = self.pulseaudio.get_source_mute(self.device_name.clone())?;
-        Ok(mute_state)
+    pub fn get_mute_state(&mut self) -> Result<bool, MewtureError> {
+        self.pulseaudio.get_source_mute(PAIdent::Name(self.device_name.clone()))
+            .map_err(|e| MewtureError::audio(&self.device_name, e))
+            .map_err(|e| self.failed(e))
     }
 
     /// Sets the mute state of the managed audio source.
@@ -44,8 +153,66 @@ impl PulseAudioHandler {
     /// # Errors
     ///
     /// Returns an error if setting the mute state fails.
-    pub fn set_mute_state(&mut self, mute_state: bool) -> Result<(), Box<dyn Error>> {
-        self.pulseaudio.set_source_mute(self.device_name.clone(), mute_state)?;
-        Ok(())
+    pub fn set_mute_state(&mut self, mute_state: bool) -> Result<(), MewtureError> {
+        // The button was pressed, that's worth an attempt right away.
+        if self.health != AudioHealth::Connected {
+            self.next_attempt = Instant::now();
+            self.ensure_connected();
+        }
+
+        self.pulseaudio.set_source_mute(PAIdent::Name(self.device_name.clone()), mute_state)
+            .map_err(|e| MewtureError::audio(&self.device_name, e))
+            .map_err(|e| self.failed(e))
+    }
+
+    /// The managed source's description, e.g. "Blue Yeti Analog Stereo", or its name when it has
+    /// none or can't be found.
+    pub fn description(&mut self) -> String {
+        match self.pulseaudio.get_source_info(PAIdent::Name(self.device_name.clone())) {
+            Ok(info) => info.description.unwrap_or_else(|| self.device_name.clone()),
+            Err(_) => self.device_name.clone()
+        }
+    }
+
+    /// Names the apps recording from the managed source right now, by their `application.name`
+    /// (or stream name). Paused (corked) streams don't count.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the source or its streams can't be listed.
+    pub fn recording_apps(&mut self) -> Result<Vec<String>, MewtureError> {
+        let source = self.pulseaudio.get_source_info(PAIdent::Name(self.device_name.clone()))
+            .map_err(|e| MewtureError::audio(&self.device_name, e))
+            .map_err(|e| self.failed(e))?;
+        let outputs = self.pulseaudio.get_source_output_info_list()
+            .map_err(|e| MewtureError::audio(&self.device_name, e))
+            .map_err(|e| self.failed(e))?;
+
+        let mut apps: Vec<String> = outputs.into_iter()
+            .filter(|output| output.source == source.index && !output.corked)
+            .map(|output| match output.proplist.get("application.name") {
+                Some(app) => app.to_string(),
+                None => output.name.unwrap_or_else(|| format!("stream #{}", output.index))
+            })
+            .collect();
+        apps.sort();
+        apps.dedup();
+
+        Ok(apps)
+    }
+
+    /// Sets the default source back to what it was before `new` changed it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if setting the default source fails.
+    pub fn restore_default_source(&mut self) -> Result<(), MewtureError> {
+        match &self.original_default_source {
+            Some(name) if *name != self.device_name => {
+                self.pulseaudio.set_default_source(PAIdent::Name(name.clone()))
+                    .map_err(|e| MewtureError::audio(name, e))
+            },
+            _ => Ok(())
+        }
     }
 }
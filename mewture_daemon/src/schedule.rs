@@ -0,0 +1,263 @@
+use chrono::{Datelike, Duration, Local, NaiveDateTime, NaiveTime};
+use mewture_shared::{ScheduleAction, ScheduleMode, ScheduleRule};
+
+/// Where the schedule gets the time from, so it can be driven by something other than the
+/// wall clock.
+pub trait Clock {
+    /// The local date and time.
+    fn now(&self) -> NaiveDateTime;
+}
+
+/// The system's local time.
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// A `ScheduleRule` with its times and days parsed.
+struct Rule {
+    /// Days from Monday the window may start on.
+    days: Vec<u32>,
+    from: NaiveTime,
+    to: NaiveTime,
+    mute: bool,
+    enforce: bool
+}
+
+impl Rule {
+    /// The windows starting from yesterday to a week ahead, as (start, end).
+    fn windows(&self, now: NaiveDateTime) -> impl Iterator<Item = (NaiveDateTime, NaiveDateTime)> + '_ {
+        let today = now.date();
+        (-1..=7)
+            .map(move |offset| today + Duration::days(offset))
+            .filter(|day| self.days.contains(&day.weekday().num_days_from_monday()))
+            .map(|day| {
+                let start = day.and_time(self.from);
+                let end = match self.to > self.from {
+                    true => day.and_time(self.to),
+                    false => (day + Duration::days(1)).and_time(self.to)
+                };
+                (start, end)
+            })
+    }
+}
+
+/// Mutes and unmutes on a schedule, see `ScheduleRule`.
+/// When windows overlap, the rule listed last wins.
+pub struct Schedule {
+    rules: Vec<Rule>,
+    clock: Box<dyn Clock>,
+    /// When `wanted` last ran, to catch windows starting in between.
+    last_checked: Option<NaiveDateTime>,
+    /// The button took over until then.
+    overridden_until: Option<NaiveDateTime>
+}
+
+impl Schedule {
+    /// Builds the schedule, `None` when there are no rules.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a rule's times or days are invalid.
+    pub fn new(rules: &[ScheduleRule], clock: Box<dyn Clock>) -> Result<Option<Self>, String> {
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        let rules = rules.iter()
+            .map(|rule| {
+                let (from_hour, from_minute) = rule.start()?;
+                let (to_hour, to_minute) = rule.end()?;
+                Ok(Rule {
+                    days: rule.weekdays()?,
+                    from: NaiveTime::from_hms_opt(from_hour, from_minute, 0).ok_or("Invalid schedule time")?,
+                    to: NaiveTime::from_hms_opt(to_hour, to_minute, 0).ok_or("Invalid schedule time")?,
+                    mute: rule.action == ScheduleAction::Mute,
+                    enforce: rule.mode == ScheduleMode::Enforce
+                })
+            })
+            .collect::<Result<Vec<Rule>, String>>()?;
+
+        Ok(Some(Self { rules, clock, last_checked: None, overridden_until: None }))
+    }
+
+    /// The button was pressed: leave the source alone until the next window starts or ends.
+    pub fn button_pressed(&mut self) {
+        let now = self.clock.now();
+        self.overridden_until = self.next_boundary(now);
+    }
+
+    /// The mute state the schedule wants now, if it differs from `current_mute_state`.
+    pub fn wanted(&mut self, current_mute_state: bool) -> Option<bool> {
+        let now = self.clock.now();
+        let last_checked = self.last_checked.replace(now);
+
+        match self.overridden_until {
+            Some(until) if now < until => return None,
+            Some(_) => self.overridden_until = None,
+            None => {}
+        }
+
+        let mut wanted = None;
+        for rule in &self.rules {
+            for (start, end) in rule.windows(now) {
+                let applies = match rule.enforce {
+                    true => start <= now && now < end,
+                    // Only when it started since the last check, not on the daemon starting.
+                    false => last_checked.is_some_and(|last| last < start && start <= now)
+                };
+                if applies {
+                    wanted = Some(rule.mute);
+                }
+            }
+        }
+
+        wanted.filter(|wanted| *wanted != current_mute_state)
+    }
+
+    /// The next time after `now` any window starts or ends.
+    fn next_boundary(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.rules.iter()
+            .flat_map(|rule| rule.windows(now))
+            .flat_map(|(start, end)| [start, end])
+            .filter(|time| *time > now)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A clock that only moves when told to.
+    #[derive(Clone)]
+    struct FixedClock(Rc<Cell<NaiveDateTime>>);
+
+    impl FixedClock {
+        /// At `hour:minute` on Monday, 1 January 2024, plus `days`.
+        fn at(days: u32, hour: u32, minute: u32) -> Self {
+            Self(Rc::new(Cell::new(time(days, hour, minute))))
+        }
+
+        fn set(&self, days: u32, hour: u32, minute: u32) {
+            self.0.set(time(days, hour, minute));
+        }
+    }
+
+    impl Clock for FixedClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0.get()
+        }
+    }
+
+    fn time(days: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 1 + days).unwrap().and_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn rule(from: &str, to: &str, action: ScheduleAction, mode: ScheduleMode) -> ScheduleRule {
+        ScheduleRule { days: vec![], from: from.to_string(), to: to.to_string(), action, mode }
+    }
+
+    fn schedule(rules: &[ScheduleRule], clock: &FixedClock) -> Schedule {
+        Schedule::new(rules, Box::new(clock.clone())).unwrap().unwrap()
+    }
+
+    #[test]
+    fn no_rules_no_schedule() {
+        assert!(Schedule::new(&[], Box::new(FixedClock::at(0, 12, 0))).unwrap().is_none());
+    }
+
+    #[test]
+    fn window_runs_past_midnight() {
+        let clock = FixedClock::at(0, 21, 59);
+        let mut schedule = schedule(&[rule("22:00", "06:00", ScheduleAction::Mute, ScheduleMode::Enforce)], &clock);
+
+        assert_eq!(schedule.wanted(false), None);
+        clock.set(0, 22, 0);
+        assert_eq!(schedule.wanted(false), Some(true));
+        clock.set(1, 5, 59);
+        assert_eq!(schedule.wanted(false), Some(true));
+        // Nothing to change when it's muted already.
+        assert_eq!(schedule.wanted(true), None);
+        clock.set(1, 6, 0);
+        assert_eq!(schedule.wanted(false), None);
+    }
+
+    #[test]
+    fn only_starts_on_the_listed_days() {
+        let clock = FixedClock::at(0, 22, 30);
+        let mut rule = rule("22:00", "06:00", ScheduleAction::Mute, ScheduleMode::Enforce);
+        rule.days = vec!["sun".to_string()];
+        let mut schedule = schedule(&[rule], &clock);
+
+        // Monday evening isn't in it, but Sunday's window runs into Monday morning.
+        assert_eq!(schedule.wanted(false), None);
+        clock.set(0, 1, 0);
+        assert_eq!(schedule.wanted(false), Some(true));
+    }
+
+    #[test]
+    fn enforce_applies_for_the_whole_window() {
+        let clock = FixedClock::at(0, 10, 0);
+        let mut schedule = schedule(&[rule("09:00", "17:00", ScheduleAction::Mute, ScheduleMode::Enforce)], &clock);
+
+        assert_eq!(schedule.wanted(false), Some(true));
+        clock.set(0, 16, 0);
+        assert_eq!(schedule.wanted(false), Some(true));
+    }
+
+    #[test]
+    fn start_only_applies_when_the_window_starts() {
+        let clock = FixedClock::at(0, 10, 0);
+        let mut schedule = schedule(&[rule("09:00", "17:00", ScheduleAction::Mute, ScheduleMode::Start)], &clock);
+
+        // Starting up inside the window leaves it alone.
+        assert_eq!(schedule.wanted(false), None);
+        clock.set(1, 8, 59);
+        assert_eq!(schedule.wanted(false), None);
+        clock.set(1, 9, 1);
+        assert_eq!(schedule.wanted(false), Some(true));
+        // Once started, unmuting sticks.
+        clock.set(1, 9, 2);
+        assert_eq!(schedule.wanted(false), None);
+    }
+
+    #[test]
+    fn button_overrides_until_the_next_boundary() {
+        let clock = FixedClock::at(0, 10, 0);
+        let mut schedule = schedule(&[
+            rule("09:00", "17:00", ScheduleAction::Mute, ScheduleMode::Enforce),
+            rule("12:00", "13:00", ScheduleAction::Mute, ScheduleMode::Enforce)
+        ], &clock);
+
+        assert_eq!(schedule.wanted(false), Some(true));
+        schedule.button_pressed();
+        assert_eq!(schedule.wanted(false), None);
+        clock.set(0, 11, 59);
+        assert_eq!(schedule.wanted(false), None);
+        // The second window starting is the next boundary.
+        clock.set(0, 12, 0);
+        assert_eq!(schedule.wanted(false), Some(true));
+    }
+
+    #[test]
+    fn last_overlapping_rule_wins() {
+        let clock = FixedClock::at(0, 10, 0);
+        let mut schedule = schedule(&[
+            rule("09:00", "17:00", ScheduleAction::Mute, ScheduleMode::Enforce),
+            rule("12:00", "13:00", ScheduleAction::Unmute, ScheduleMode::Enforce)
+        ], &clock);
+
+        assert_eq!(schedule.wanted(false), Some(true));
+        clock.set(0, 12, 30);
+        assert_eq!(schedule.wanted(true), Some(false));
+        clock.set(0, 13, 0);
+        assert_eq!(schedule.wanted(false), Some(true));
+    }
+}
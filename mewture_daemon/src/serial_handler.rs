@@ -1,37 +1,50 @@
-extern crate serialport;
-
-use serialport::SerialPort;
-use std::error::Error;
-use std::io::Write;
+use mewture_shared::SerialSettings;
+use std::io;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::error::MewtureError;
 
 pub struct SerialHandler {
-    port: Box<dyn SerialPort>
+    port: SerialStream,
+    port_path: String,
+    timeout: Duration
 }
 
-// Abstract the serialport crate for future testing?
+// Abstract the tokio-serial crate for future testing?
 impl SerialHandler {
-    pub fn new(port_path: &str, baud_rate: u32) -> Result<Self, Box<dyn Error>> {
-        let port = serialport::new(port_path, baud_rate)
-            .timeout(Duration::from_millis(300))
-            .open()?;
+    pub fn new(port_path: &str, settings: &SerialSettings) -> Result<Self, MewtureError> {
+        let mut port = settings.builder(port_path)
+            .map_err(|e| MewtureError::transport(port_path, io::Error::new(io::ErrorKind::InvalidInput, e)))?
+            .open_native_async()
+            .map_err(|e| MewtureError::transport(port_path, e))?;
+        settings.apply_control_lines(&mut port)
+            .map_err(|e| MewtureError::transport(port_path, e))?;
 
-        Ok(Self { port })
+        Ok(Self { port, port_path: port_path.to_string(), timeout: settings.timeout() })
     }
 
-    // Replicate the base functionality of serialport.
-    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Box<dyn Error>> {
-        let bytes_read = self.port.read(buffer)?;
-        Ok(bytes_read)
+    pub fn port_path(&self) -> &str {
+        &self.port_path
     }
 
-    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, Box<dyn Error>> {
-        let bytes_written = self.port.write(buffer)?;
-        Ok(bytes_written)
+    // Replicate the base functionality of serialport.
+    // Reading nothing means the other end went away, since a read only completes once there is data.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, MewtureError> {
+        match self.port.read(buffer).await {
+            Ok(0) => Err(MewtureError::Disconnected { port: self.port_path.clone() }),
+            Ok(bytes_read) => Ok(bytes_read),
+            Err(e) => Err(MewtureError::transport(&self.port_path, e))
+        }
     }
 
-    // Return the name (path) of the serialport.
-    pub fn get_name(&mut self) -> Option<String> {
-        self.port.name()
+    // Writes the whole buffer, giving up after the timeout so a stuck button can't hang the daemon.
+    pub async fn write(&mut self, buffer: &[u8]) -> Result<usize, MewtureError> {
+        match tokio::time::timeout(self.timeout, self.port.write_all(buffer)).await {
+            Ok(Ok(_)) => Ok(buffer.len()),
+            Ok(Err(e)) => Err(MewtureError::transport(&self.port_path, e)),
+            Err(e) => Err(MewtureError::transport(&self.port_path, std::io::Error::from(e)))
+        }
     }
 }
@@ -0,0 +1,32 @@
+use std::io;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Listens for the signals that ask the daemon to stop:
+/// SIGTERM (systemd stopping the unit) and SIGINT (Ctrl+C when run from a terminal).
+pub struct ShutdownSignal {
+    terminate: Signal,
+    interrupt: Signal
+}
+
+impl ShutdownSignal {
+    /// Installs the signal handlers. From here on these signals no longer kill the process,
+    /// so `recv` has to be awaited anywhere the daemon might wait for a while.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a handler could not be installed.
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?
+        })
+    }
+
+    /// Waits until either signal is received.
+    pub async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.terminate.recv() => {}
+            _ = self.interrupt.recv() => {}
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::PathBuf;
+
+/// Keeps the last mute state the user chose on disk, so it survives the daemon restarting and
+/// reboots. Changes the daemon makes on its own (the schedule, the screen lock, calls) aren't
+/// kept, locking the screen before a shutdown shouldn't mean starting muted.
+/// The file holds `muted` or `unmuted`; failing to read or write it is logged, not fatal.
+pub struct StateFile {
+    path: PathBuf,
+    /// The mute state last seen, to act on changes only.
+    seen: Option<bool>,
+    /// The mute state the daemon set itself, yet to show up.
+    automatic: Option<bool>
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, seen: None, automatic: None }
+    }
+
+    /// The mute state saved last, if there is one.
+    pub fn load(&mut self) -> Option<bool> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("No saved mute state in {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        let muted = match content.trim() {
+            "muted" => Some(true),
+            "unmuted" => Some(false),
+            other => {
+                eprintln!("Ignoring unknown mute state {:?} in {}", other, self.path.display());
+                None
+            }
+        };
+        self.seen = muted;
+        muted
+    }
+
+    /// The daemon set `muted` on its own, don't save it when it shows up.
+    pub fn set_by_daemon(&mut self, muted: bool) {
+        // No change will show up if it was that way already.
+        self.automatic = match self.seen == Some(muted) {
+            true => None,
+            false => Some(muted)
+        };
+    }
+
+    /// Saves `muted` if it's a change, unless the daemon made it.
+    pub fn update(&mut self, muted: bool) {
+        if self.seen.replace(muted) == Some(muted) {
+            return;
+        }
+        // Either the daemon's change showed up, or another one came instead and it's moot.
+        if self.automatic.take() == Some(muted) {
+            return;
+        }
+
+        let content = if muted { "muted\n" } else { "unmuted\n" };
+        let result = match self.path.parent() {
+            Some(dir) => fs::create_dir_all(dir),
+            None => Ok(())
+        };
+        if let Err(e) = result.and_then(|_| mewture_shared::write_atomically(&self.path, content)) {
+            eprintln!("Could not save the mute state to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn state_file(dir: &TempDir) -> StateFile {
+        StateFile::new(dir.path().join("mewture/state"))
+    }
+
+    fn saved(dir: &TempDir) -> Option<String> {
+        fs::read_to_string(dir.path().join("mewture/state")).ok()
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(state_file(&dir).load(), None);
+
+        state_file(&dir).update(true);
+        assert_eq!(saved(&dir).as_deref(), Some("muted\n"));
+        assert_eq!(state_file(&dir).load(), Some(true));
+
+        fs::write(dir.path().join("mewture/state"), "sideways\n").unwrap();
+        assert_eq!(state_file(&dir).load(), None);
+    }
+
+    #[test]
+    fn saves_changes_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("mewture")).unwrap();
+        fs::write(dir.path().join("mewture/state"), "unmuted\n").unwrap();
+        let mut state = state_file(&dir);
+        state.load();
+
+        // Written behind our back, to tell whether it's written again.
+        fs::write(dir.path().join("mewture/state"), "untouched").unwrap();
+        state.update(false);
+        assert_eq!(saved(&dir).as_deref(), Some("untouched"));
+        state.update(true);
+        assert_eq!(saved(&dir).as_deref(), Some("muted\n"));
+    }
+
+    #[test]
+    fn doesnt_save_what_the_daemon_did() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = state_file(&dir);
+        state.update(false);
+
+        state.set_by_daemon(true);
+        state.update(false);
+        state.update(true);
+        assert_eq!(saved(&dir).as_deref(), Some("unmuted\n"));
+
+        // The user's next change is saved as usual.
+        state.update(false);
+        state.update(true);
+        assert_eq!(saved(&dir).as_deref(), Some("muted\n"));
+    }
+
+    #[test]
+    fn forgets_a_change_that_never_showed_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = state_file(&dir);
+        state.update(true);
+
+        // Muted already, so nothing shows up, and the user's mute later on counts.
+        state.set_by_daemon(true);
+        state.update(false);
+        state.update(true);
+        assert_eq!(saved(&dir).as_deref(), Some("muted\n"));
+    }
+}
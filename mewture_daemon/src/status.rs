@@ -0,0 +1,199 @@
+use ddaa_protocol::MessageType;
+use mewture_shared::StatusMap;
+
+use crate::error::MewtureError;
+use crate::pulseaudio_handler::AudioHealth;
+use crate::transport::Transport;
+
+/// DDAA variable holding the host's status, see `Status`.
+pub const STATUS_VARIABLE: u8 = 0x02;
+
+/// What the button should show, more than the mute state alone can tell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Muted,
+    Unmuted,
+    NoDevice,
+    ServerDown,
+    InCall,
+    PushToTalk,
+    Locked
+}
+
+impl Status {
+    /// Works out the status, the first that applies of: the server being down, the source
+    /// missing, push-to-talk held, muted with the screen locked, muted, in a call, unmuted.
+    pub fn of(health: AudioHealth, muted: bool, in_call: bool, push_to_talk: bool, locked: bool) -> Self {
+        match health {
+            AudioHealth::ServerDown => Status::ServerDown,
+            AudioHealth::NoDevice => Status::NoDevice,
+            AudioHealth::Connected if push_to_talk => Status::PushToTalk,
+            AudioHealth::Connected if muted && locked => Status::Locked,
+            AudioHealth::Connected if muted => Status::Muted,
+            AudioHealth::Connected if in_call => Status::InCall,
+            AudioHealth::Connected => Status::Unmuted
+        }
+    }
+
+    /// The value the config maps this status to.
+    pub fn value(self, map: &StatusMap) -> u8 {
+        match self {
+            Status::Muted => map.muted,
+            Status::Unmuted => map.unmuted,
+            Status::NoDevice => map.no_device,
+            Status::ServerDown => map.server_down,
+            Status::InCall => map.in_call,
+            Status::PushToTalk => map.push_to_talk,
+            Status::Locked => map.locked
+        }
+    }
+}
+
+/// Keeps the button's status variable up to date, writing it only when it changes.
+pub struct StatusReporter {
+    map: Option<StatusMap>,
+    /// The value the button has, `None` until it's been told.
+    sent: Option<u8>,
+    /// Whether push-to-talk is held, set by the button.
+    pub push_to_talk: bool,
+    /// Whether something is recording from the source.
+    pub in_call: bool,
+    /// Whether the screen is locked.
+    pub locked: bool
+}
+
+impl StatusReporter {
+    /// Reports with `map`, or does nothing at all without one.
+    pub fn new(map: Option<StatusMap>) -> Self {
+        Self { map, sent: None, push_to_talk: false, in_call: false, locked: false }
+    }
+
+    /// Forgets what the button was told, for a button that just connected.
+    pub fn reset(&mut self) {
+        self.sent = None;
+    }
+
+    /// Sends the button the current status if it differs from what it was last told.
+    /// Without a button connected, it's sent once one is.
+    pub async fn update(
+        &mut self,
+        port: Option<&mut Transport>,
+        health: AudioHealth,
+        muted: bool,
+        debug: bool
+    ) -> Result<(), MewtureError> {
+        let (map, port) = match (&self.map, port) {
+            (Some(map), Some(port)) => (map, port),
+            _ => {
+                self.sent = None;
+                return Ok(());
+            }
+        };
+
+        let status = Status::of(health, muted, self.in_call, self.push_to_talk, self.locked);
+        let value = status.value(map);
+        if self.sent == Some(value) {
+            return Ok(());
+        }
+
+        if debug {
+            println!("Setting status variable to {:?} ({:#04x})", status, value);
+        }
+
+        let buffer = ddaa_protocol::create_protocol_buffer(
+            MessageType::Request,
+            ddaa_protocol::Command::Write,
+            STATUS_VARIABLE,
+            &[value]
+        );
+        port.write(&buffer).await?;
+        self.sent = Some(value);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tcp_handler::TcpHandler;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn audio_problems_come_first() {
+        assert_eq!(Status::of(AudioHealth::ServerDown, true, true, true, true), Status::ServerDown);
+        assert_eq!(Status::of(AudioHealth::NoDevice, true, true, true, true), Status::NoDevice);
+    }
+
+    #[test]
+    fn then_push_to_talk_then_locked_then_muted() {
+        assert_eq!(Status::of(AudioHealth::Connected, true, true, true, true), Status::PushToTalk);
+        assert_eq!(Status::of(AudioHealth::Connected, true, true, false, true), Status::Locked);
+        assert_eq!(Status::of(AudioHealth::Connected, true, true, false, false), Status::Muted);
+    }
+
+    #[test]
+    fn in_call_only_when_unmuted() {
+        assert_eq!(Status::of(AudioHealth::Connected, false, true, false, true), Status::InCall);
+        assert_eq!(Status::of(AudioHealth::Connected, false, false, false, true), Status::Unmuted);
+    }
+
+    #[test]
+    fn maps_every_status() {
+        let map = StatusMap { locked: 0x42, ..Default::default() };
+        assert_eq!(Status::Locked.value(&map), 0x42);
+        assert_eq!(Status::ServerDown.value(&map), 0x03);
+        assert_eq!(Status::Unmuted.value(&map), 0x01);
+    }
+
+    /// A button connected over TCP, and the other end of it.
+    async fn button() -> (Transport, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (handler, accepted) = tokio::join!(TcpHandler::connect(&address, Duration::from_secs(5)), listener.accept());
+        (Transport::Tcp(handler.unwrap()), accepted.unwrap().0)
+    }
+
+    /// Whatever the button was sent, waiting a little for it, without `TcpHandler`'s length prefix.
+    async fn received(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buffer = vec![0; 64];
+        match tokio::time::timeout(Duration::from_millis(200), stream.read(&mut buffer)).await {
+            Ok(read) => buffer[2..read.unwrap()].to_vec(),
+            Err(_) => vec![]
+        }
+    }
+
+    fn status_message(value: u8) -> Vec<u8> {
+        ddaa_protocol::create_protocol_buffer(MessageType::Request, ddaa_protocol::Command::Write, STATUS_VARIABLE, &[value])
+    }
+
+    #[tokio::test]
+    async fn only_sends_changes() {
+        let (mut port, mut stream) = button().await;
+        let mut reporter = StatusReporter::new(Some(StatusMap::default()));
+
+        reporter.update(Some(&mut port), AudioHealth::Connected, true, false).await.unwrap();
+        assert_eq!(received(&mut stream).await, status_message(0x00));
+        reporter.update(Some(&mut port), AudioHealth::Connected, true, false).await.unwrap();
+        assert_eq!(received(&mut stream).await, vec![]);
+
+        reporter.in_call = true;
+        reporter.update(Some(&mut port), AudioHealth::Connected, false, false).await.unwrap();
+        assert_eq!(received(&mut stream).await, status_message(0x04));
+
+        reporter.reset();
+        reporter.update(Some(&mut port), AudioHealth::Connected, false, false).await.unwrap();
+        assert_eq!(received(&mut stream).await, status_message(0x04));
+    }
+
+    #[tokio::test]
+    async fn sends_nothing_without_a_map() {
+        let (mut port, mut stream) = button().await;
+        let mut reporter = StatusReporter::new(None);
+
+        reporter.update(Some(&mut port), AudioHealth::ServerDown, true, false).await.unwrap();
+        assert_eq!(received(&mut stream).await, vec![]);
+    }
+}
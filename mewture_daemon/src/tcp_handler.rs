@@ -0,0 +1,199 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::MewtureError;
+
+/// Size of the length prefix in front of every DDAA frame.
+const LENGTH_PREFIX: usize = 2;
+
+/// A button connected over TCP. Every DDAA frame goes over the stream with its length in front,
+/// as a big-endian `u16`, since a stream doesn't keep message boundaries the way a tty read
+/// usually does.
+pub struct TcpHandler {
+    stream: TcpStream,
+    peer: String,
+    timeout: Duration,
+    /// Bytes read that don't make up a whole frame yet.
+    pending: Vec<u8>
+}
+
+impl TcpHandler {
+    /// Connects to a button listening at `address` (`host:port`).
+    pub async fn connect(address: &str, timeout: Duration) -> Result<Self, MewtureError> {
+        let stream = match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(MewtureError::transport(address, e)),
+            Err(e) => return Err(MewtureError::transport(address, io::Error::from(e)))
+        };
+
+        Ok(Self::new(stream, address.to_string(), timeout))
+    }
+
+    fn new(stream: TcpStream, peer: String, timeout: Duration) -> Self {
+        // Frames are tiny and latency is what matters.
+        let _ = stream.set_nodelay(true);
+        Self { stream, peer, timeout, pending: vec![] }
+    }
+
+    /// Where the button connected from, or was connected to.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    // Same as `SerialHandler::read`, one whole frame at a time.
+    // Only ever awaits a single read of the stream, so it can be cancelled without losing data.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, MewtureError> {
+        loop {
+            if self.pending.len() >= LENGTH_PREFIX {
+                let length = usize::from(u16::from_be_bytes([self.pending[0], self.pending[1]]));
+                if length > buffer.len() {
+                    // There's no telling where the next frame starts, start over.
+                    return Err(MewtureError::Disconnected { port: self.peer.clone() });
+                }
+                if self.pending.len() >= LENGTH_PREFIX + length {
+                    buffer[..length].copy_from_slice(&self.pending[LENGTH_PREFIX..LENGTH_PREFIX + length]);
+                    self.pending.drain(..LENGTH_PREFIX + length);
+                    return Ok(length);
+                }
+            }
+
+            let mut chunk = [0u8; 256];
+            match self.stream.read(&mut chunk).await {
+                Ok(0) => return Err(MewtureError::Disconnected { port: self.peer.clone() }),
+                Ok(bytes_read) => self.pending.extend_from_slice(&chunk[..bytes_read]),
+                Err(e) => return Err(MewtureError::transport(&self.peer, e))
+            }
+        }
+    }
+
+    // Writes one frame, giving up after the timeout so a stuck button can't hang the daemon.
+    pub async fn write(&mut self, buffer: &[u8]) -> Result<usize, MewtureError> {
+        let length = match u16::try_from(buffer.len()) {
+            Ok(length) => length,
+            Err(_) => return Err(MewtureError::Protocol { message: format!("Frame too long: {} bytes", buffer.len()) })
+        };
+        let mut frame = Vec::with_capacity(LENGTH_PREFIX + buffer.len());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame.extend_from_slice(buffer);
+
+        match tokio::time::timeout(self.timeout, self.stream.write_all(&frame)).await {
+            Ok(Ok(_)) => Ok(buffer.len()),
+            Ok(Err(e)) => Err(MewtureError::transport(&self.peer, e)),
+            Err(e) => Err(MewtureError::transport(&self.peer, io::Error::from(e)))
+        }
+    }
+}
+
+/// Listens for buttons that connect to us, for boards that can't be reached (behind NAT, on
+/// DHCP) but know where the host is.
+pub struct TcpServer {
+    listener: TcpListener,
+    address: String,
+    timeout: Duration
+}
+
+impl TcpServer {
+    /// Starts listening on `address` (`host:port`).
+    pub async fn bind(address: &str, timeout: Duration) -> Result<Self, MewtureError> {
+        let listener = TcpListener::bind(address).await
+            .map_err(|e| MewtureError::transport(address, e))?;
+
+        Ok(Self { listener, address: address.to_string(), timeout })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Waits for the next button to connect.
+    pub async fn accept(&mut self) -> Result<TcpHandler, MewtureError> {
+        let (stream, peer) = self.listener.accept().await
+            .map_err(|e| MewtureError::transport(&self.address, e))?;
+
+        Ok(TcpHandler::new(stream, peer.to_string(), self.timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A handler connected to a server we hold the other end of.
+    async fn pair() -> (TcpHandler, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (handler, accepted) = tokio::join!(TcpHandler::connect(&address, TIMEOUT), listener.accept());
+        (handler.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_sent_in_pieces() {
+        let (mut handler, mut button) = pair().await;
+        let reader = tokio::spawn(async move {
+            let mut buffer = [0u8; 16];
+            let length = handler.read(&mut buffer).await.unwrap();
+            buffer[..length].to_vec()
+        });
+
+        for piece in [&[0u8][..], &[3, 1], &[2, 3]] {
+            button.write_all(piece).await.unwrap();
+            button.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(reader.await.unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn reads_two_frames_from_one_read() {
+        let (mut handler, mut button) = pair().await;
+        button.write_all(&[0, 2, 1, 2, 0, 1, 9]).await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        assert_eq!(handler.read(&mut buffer).await.unwrap(), 2);
+        assert_eq!(&buffer[..2], &[1, 2]);
+        assert_eq!(handler.read(&mut buffer).await.unwrap(), 1);
+        assert_eq!(&buffer[..1], &[9]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_frame_longer_than_the_buffer() {
+        let (mut handler, mut button) = pair().await;
+        button.write_all(&[0, 17]).await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        assert!(matches!(handler.read(&mut buffer).await, Err(MewtureError::Disconnected { .. })));
+    }
+
+    #[tokio::test]
+    async fn reports_the_button_hanging_up() {
+        let (mut handler, button) = pair().await;
+        drop(button);
+
+        let mut buffer = [0u8; 16];
+        assert!(matches!(handler.read(&mut buffer).await, Err(MewtureError::Disconnected { .. })));
+    }
+
+    #[tokio::test]
+    async fn server_accepts_a_button_and_frames_writes() {
+        let mut server = TcpServer::bind("127.0.0.1:0", TIMEOUT).await.unwrap();
+        let address = server.listener.local_addr().unwrap();
+        let (accepted, button) = tokio::join!(server.accept(), TcpStream::connect(address));
+        let (mut handler, mut button) = (accepted.unwrap(), button.unwrap());
+        assert_eq!(handler.peer(), button.local_addr().unwrap().to_string());
+
+        assert_eq!(handler.write(&[4, 5, 6]).await.unwrap(), 3);
+        let mut frame = [0u8; 5];
+        button.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0, 3, 4, 5, 6]);
+
+        button.write_all(&[0, 1, 7]).await.unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(handler.read(&mut buffer).await.unwrap(), 1);
+        assert_eq!(buffer[0], 7);
+    }
+}
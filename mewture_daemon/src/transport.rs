@@ -0,0 +1,190 @@
+use mewture_shared::{Config, SerialSettings, TcpMode};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::error::MewtureError;
+use crate::hotplug::{DeviceMatcher, Hotplug, HotplugEvent};
+use crate::serial_handler::SerialHandler;
+use crate::tcp_handler::{TcpHandler, TcpServer};
+
+/// How long to wait between attempts to connect to a button that's offline.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An open connection to the button, whichever way it's reached.
+/// The request handlers only ever read and write frames, so they work the same over both.
+pub enum Transport {
+    Serial(SerialHandler),
+    Tcp(TcpHandler)
+}
+
+impl Transport {
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, MewtureError> {
+        match self {
+            Transport::Serial(port) => port.read(buffer).await,
+            Transport::Tcp(stream) => stream.read(buffer).await
+        }
+    }
+
+    pub async fn write(&mut self, buffer: &[u8]) -> Result<usize, MewtureError> {
+        match self {
+            Transport::Serial(port) => port.write(buffer).await,
+            Transport::Tcp(stream) => stream.write(buffer).await
+        }
+    }
+
+    /// The serial port's path, or the TCP peer's address.
+    pub fn name(&self) -> &str {
+        match self {
+            Transport::Serial(port) => port.port_path(),
+            Transport::Tcp(stream) => stream.peer()
+        }
+    }
+}
+
+/// Something happened to the button, see `Link::next`.
+pub enum LinkEvent {
+    /// The button may be reachable at the given place now, try opening it.
+    Added(String),
+    /// The button went away.
+    Removed,
+    /// The button connected to us, or we connected to it. Boxed, a connection dwarfs the other events.
+    Connected(Box<Transport>)
+}
+
+/// How to get hold of the button, and how to find out it came or went.
+pub enum Link {
+    /// A serial port, watched through udev (boxed, the monitor takes up most of the room).
+    Serial {
+        hotplug: Box<Hotplug>,
+        settings: SerialSettings
+    },
+    /// Connect to the button at `address`. That's done in the background, as a button that's
+    /// offline can take up to `timeout` to give up on, and it's handed over through `next`.
+    TcpClient {
+        address: String,
+        timeout: Duration,
+        /// Keeps trying until the button answers, see `connect_in_background`.
+        connecting: Option<JoinHandle<TcpHandler>>
+    },
+    /// Wait for the button to connect to us.
+    TcpServer(TcpServer)
+}
+
+impl Link {
+    /// Sets up the link the config asks for: TCP when `[tcp]` is set, the serial port otherwise.
+    pub async fn from_config(config: &Config) -> Result<Self, MewtureError> {
+        match &config.tcp {
+            Some(tcp) => match tcp.mode {
+                TcpMode::Client => Ok(Link::TcpClient {
+                    address: tcp.address.clone(),
+                    timeout: tcp.timeout(),
+                    connecting: None
+                }),
+                TcpMode::Server => Ok(Link::TcpServer(TcpServer::bind(&tcp.address, tcp.timeout()).await?))
+            },
+            None => Ok(Link::Serial {
+                hotplug: Box::new(Hotplug::new(DeviceMatcher::new(&config.serial_port, config.device.clone()))),
+                settings: config.serial.clone()
+            })
+        }
+    }
+
+    /// Where to open the button right now, `None` if it comes through `next` instead.
+    pub fn resolve(&self) -> Option<String> {
+        match self {
+            Link::Serial { hotplug, .. } => Some(hotplug.resolve()),
+            Link::TcpClient { .. } | Link::TcpServer(_) => None
+        }
+    }
+
+    /// A human-readable description of where the button is expected.
+    pub fn describe(&self) -> String {
+        match self {
+            Link::Serial { hotplug, .. } => hotplug.describe(),
+            Link::TcpClient { address, .. } => address.clone(),
+            Link::TcpServer(server) => format!("a button to connect to {}", server.address())
+        }
+    }
+
+    /// Opens the button at `target`, as given by `resolve` or `LinkEvent::Added`.
+    pub async fn open(&mut self, target: &str) -> Result<Transport, MewtureError> {
+        match self {
+            Link::Serial { hotplug, settings } => {
+                let port = SerialHandler::new(target, settings)?;
+                hotplug.learn(target);
+                Ok(Transport::Serial(port))
+            },
+            Link::TcpClient { timeout, .. } => Ok(Transport::Tcp(TcpHandler::connect(target, *timeout).await?)),
+            Link::TcpServer(server) => Err(MewtureError::Protocol {
+                message: format!("Buttons connect to {} by themselves", server.address())
+            })
+        }
+    }
+
+    /// Waits for the button to come or go. Never returns when there's no way to find out, the
+    /// caller polls `resolve` for those. A button we connect to is only looked for while it
+    /// isn't `connected` already.
+    /// Can be cancelled, a connection underway carries on and is handed over by the next call.
+    pub async fn next(&mut self, connected: bool) -> Result<LinkEvent, MewtureError> {
+        match self {
+            Link::Serial { hotplug, .. } => match hotplug.next().await {
+                HotplugEvent::Added(node) => Ok(LinkEvent::Added(node.display().to_string())),
+                HotplugEvent::Removed => Ok(LinkEvent::Removed)
+            },
+            Link::TcpClient { .. } if connected => std::future::pending().await,
+            Link::TcpClient { address, timeout, connecting } => {
+                let handle = connecting.get_or_insert_with(|| connect_in_background(address.clone(), *timeout));
+                let result = handle.await;
+                *connecting = None;
+                match result {
+                    Ok(stream) => Ok(LinkEvent::Connected(Box::new(Transport::Tcp(stream)))),
+                    Err(e) => Err(MewtureError::Protocol { message: format!("Connecting to {} failed: {}", address, e) })
+                }
+            },
+            Link::TcpServer(server) => Ok(LinkEvent::Connected(Box::new(Transport::Tcp(server.accept().await?))))
+        }
+    }
+}
+
+/// Connects to the button at `address` on a task of its own, so the loop goes on meanwhile,
+/// trying again every so often until it answers.
+fn connect_in_background(address: String, timeout: Duration) -> JoinHandle<TcpHandler> {
+    tokio::spawn(async move {
+        loop {
+            match TcpHandler::connect(&address, timeout).await {
+                Ok(stream) => return stream,
+                Err(_) => tokio::time::sleep(CONNECT_RETRY_INTERVAL).await
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn client(address: String) -> Link {
+        Link::TcpClient { address, timeout: Duration::from_secs(5), connecting: None }
+    }
+
+    #[tokio::test]
+    async fn connects_in_the_background() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut link = client(listener.local_addr().unwrap().to_string());
+        assert_eq!(link.resolve(), None);
+
+        let (accepted, event) = tokio::join!(listener.accept(), link.next(false));
+        accepted.unwrap();
+        assert!(matches!(event, Ok(LinkEvent::Connected(transport)) if matches!(*transport, Transport::Tcp(_))));
+    }
+
+    #[tokio::test]
+    async fn doesnt_connect_while_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut link = client(listener.local_addr().unwrap().to_string());
+
+        assert!(tokio::time::timeout(Duration::from_millis(200), link.next(true)).await.is_err());
+        assert!(matches!(link, Link::TcpClient { connecting: None, .. }));
+    }
+}
@@ -0,0 +1,214 @@
+use ddaa_protocol::{Command, MessageType};
+use glob::glob;
+use libpulse_sys::pa_port_available_t;
+use mewture_shared::{DeviceSelector, SerialSettings};
+use pulser::simple::PulseAudio;
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType};
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to look for buttons, all ports together.
+const DISCOVERY_DEADLINE: Duration = Duration::from_secs(6);
+
+/// How often to ping a port that hasn't answered yet.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a single read waits, so pings keep going out and the deadline is noticed.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// An audio source that can be muted.
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioSource {
+    pub name: String,
+    pub description: String
+}
+
+/// A serial port with a button behind it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Button {
+    /// The path to open, a `/dev/serial/by-id/...` link when there is one.
+    pub port: String,
+    /// The USB identity, so the daemon can find the button wherever it ends up.
+    pub device: Option<DeviceSelector>
+}
+
+/// Lists the sources that have at least one input port that may be plugged in.
+///
+/// # Errors
+///
+/// Returns an error if PulseAudio can't be asked.
+pub fn find_audio_sources(pa: &PulseAudio) -> Result<Vec<AudioSource>, String> {
+    let devices: Vec<pulser::api::PASourceInfo> = pa.get_source_info_list()
+        .map_err(|e| format!("Failed to get audio devices: {}", e))?;
+
+    let mut sources: Vec<AudioSource> = vec![];
+    for dev in devices {
+        if dev.ports.is_empty() {
+            // No input ports on the device, so let's skip it.
+            continue;
+        }
+
+        let found = dev.ports.iter().any(|port|
+            port.available == pa_port_available_t::Unknown ||
+            port.available == pa_port_available_t::Yes
+        );
+
+        if found {
+            sources.push(
+                AudioSource {
+                    name: dev.name.unwrap(),
+                    description: dev.description.unwrap()
+                }
+            );
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Lists the USB serial ports.
+///
+/// # Errors
+///
+/// Returns an error if the serial ports can't be enumerated.
+pub fn usb_ports() -> Result<Vec<SerialPortInfo>, String> {
+    let ports: Vec<SerialPortInfo> = serialport::available_ports()
+        .map_err(|e| format!("Failed to enumerate serial ports: {}", e))?;
+
+    Ok(
+        ports.into_iter()
+            .filter(|port| matches!(port.port_type, SerialPortType::UsbPort(_)))
+            .collect()
+    )
+}
+
+/// Probes every USB serial port for a button at the same time, giving up on the ones that
+/// haven't answered by `DISCOVERY_DEADLINE`.
+///
+/// # Errors
+///
+/// Returns an error if the serial ports can't be enumerated.
+pub fn find_buttons(settings: &SerialSettings) -> Result<Vec<Button>, String> {
+    let deadline = Instant::now() + DISCOVERY_DEADLINE;
+    let candidates: Vec<Button> = usb_ports()?.iter().map(button_at).collect();
+
+    // Threads that are stuck (in `open`, say) are left behind rather than waited for.
+    let (sender, receiver) = mpsc::channel::<(usize, bool)>();
+    for (index, button) in candidates.iter().enumerate() {
+        let sender = sender.clone();
+        let port_path = button.port.clone();
+        let settings = settings.clone();
+        thread::spawn(move || {
+            let is_button = probe(&port_path, &settings, deadline).unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                false
+            });
+            let _ = sender.send((index, is_button));
+        });
+    }
+    drop(sender);
+
+    let mut found: Vec<bool> = vec![false; candidates.len()];
+    let mut answered = 0;
+    while answered < candidates.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok((index, is_button)) => {
+                found[index] = is_button;
+                answered += 1;
+            },
+            Err(_) => break
+        }
+    }
+
+    // Keep the order the ports were listed in.
+    Ok(
+        candidates.into_iter()
+            .zip(found)
+            .filter(|(_, is_button)| *is_button)
+            .map(|(button, _)| button)
+            .collect()
+    )
+}
+
+/// Describes the button that would be at `port`, preferring the port's stable
+/// `/dev/serial/by-id/...` link.
+pub fn button_at(port: &SerialPortInfo) -> Button {
+    let (path, device) = match &port.port_type {
+        SerialPortType::UsbPort(info) => {
+            let path = match &info.serial_number {
+                Some(sn) => match glob(&format!("/dev/serial/by-id/*{}*", sn)).expect("Glob error").next() {
+                    // We have a matching serial number, use it.
+                    Some(Ok(path)) => path.display().to_string(),
+                    // No matching link, use the port name.
+                    _ => port.port_name.clone()
+                },
+                // No serial number, just use it's port name.
+                None => port.port_name.clone()
+            };
+            (path, Some(DeviceSelector::from_usb_port_info(info)))
+        },
+        _ => (port.port_name.clone(), None)
+    };
+
+    Button { port: path, device }
+}
+
+/// Opens `port_path`, pings it and waits until `deadline` for a button to answer, or to ping us
+/// on its own. Gives up early on a port that closes, or that answers with something a button
+/// wouldn't send.
+///
+/// # Errors
+///
+/// Returns an error if the port can't be set up, written to or read from.
+fn probe(port_path: &str, settings: &SerialSettings, deadline: Instant) -> Result<bool, String> {
+    let mut port = settings.builder(port_path)?
+        .timeout(READ_TIMEOUT)
+        .open()
+        .map_err(|error| format!("Error opening serial port {}: {}", port_path, error))?;
+    settings.apply_control_lines(port.as_mut())
+        .map_err(|error| format!("Error setting DTR/RTS on serial port {}: {}", port_path, error))?;
+
+    let ping = ddaa_protocol::create_protocol_buffer(MessageType::Request, Command::Ping, 0x00, &[]);
+    let mut received_buffer: Vec<u8> = vec![0; 64];
+    let mut last_ping: Option<Instant> = None;
+
+    while Instant::now() < deadline {
+        // Ask again every so often, the first ping may have been sent while the button was
+        // still starting up (opening the port can reset it).
+        let ping_due = match last_ping {
+            Some(sent) => sent.elapsed() >= PING_INTERVAL,
+            None => true
+        };
+        if ping_due {
+            port.write_all(&ping)
+                .map_err(|error| format!("Error writing to serial port {}: {}", port_path, error))?;
+            last_ping = Some(Instant::now());
+        }
+
+        let bytes_read = match port.read(&mut received_buffer) {
+            // The device hung up.
+            Ok(0) => return Ok(false),
+            Ok(bytes_read) => bytes_read,
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => continue,
+            Err(error) => return Err(format!("Error reading from serial port {}: {}", port_path, error))
+        };
+
+        if bytes_read > 7 {
+            // Either an answer to our ping, or the button pinging us. Buttons only ever send
+            // DDAA messages, anything else is some other device.
+            match ddaa_protocol::parse_protocol_message(&mut received_buffer) {
+                Some(message) if message.command == Command::Ping && message.message_type != MessageType::ResponseError => {
+                    return Ok(true);
+                },
+                Some(_) => {},
+                None => return Ok(false)
+            }
+        }
+    }
+
+    Ok(false)
+}
@@ -1,271 +1,555 @@
 extern crate serialport;
 
-use home;
-use glob::glob;
+use clap::Parser;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::Duration;
-use dialoguer::{Select, theme::ColorfulTheme};
+use dialoguer::{Confirm, Select, theme::ColorfulTheme};
 use indicatif::{ProgressBar, ProgressStyle};
-use libpulse_sys::pa_port_available_t;
 use mewture_shared;
-use pulser::api::PAIdent;
+use mewture_shared::SerialSettings;
 use pulser::simple::PulseAudio;
-use serialport::SerialPortType;
+use serde::Serialize;
 
-#[derive(Debug)]
-struct AudioItem {
-    value: Option<String>,
-    display_text: String,
+use crate::discovery::{AudioSource, Button};
+
+mod discovery;
+mod service;
+mod verify;
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// Config file to write, instead of `$MEWTURE_CONFIG` or the one in the config directory.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Upgrade an existing config file to the current version (keeping a backup) and exit.
+    #[arg(long)]
+    migrate: bool,
+
+    /// List the audio sources that can be picked and exit.
+    #[arg(long)]
+    list_sources: bool,
+
+    /// List the buttons found on USB serial ports and exit.
+    #[arg(long)]
+    list_buttons: bool,
+
+    /// Audio source to use: its name, its index in `--list-sources`, or a pattern such as `*usb*`
+    /// matching its name or description.
+    #[arg(long)]
+    source: Option<String>,
+
+    /// The button's serial port, or `auto` for the only button found.
+    #[arg(long)]
+    port: Option<String>,
+
+    /// Don't prompt: use the only candidate for anything not given, and fail if there are several.
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Print lists and the written config as JSON.
+    #[arg(long)]
+    json: bool,
+
+    /// Change individual settings of the existing config.
+    #[arg(short, long)]
+    edit: bool,
+
+    /// Test the button against the audio source in the existing config and exit.
+    #[arg(short, long)]
+    test: bool,
+
+    /// Once the config is written, enable and (re)start the daemon's systemd user unit without
+    /// asking.
+    #[arg(long)]
+    service: bool
 }
 
-#[derive(Debug)]
-struct SerialPortItem {
-    value: Option<String>,
-    display_text: String,
+/// What `--list-sources` and `--list-buttons` print with `--json`.
+#[derive(Serialize)]
+struct Listing {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<Vec<AudioSource>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buttons: Option<Vec<Button>>
+}
+
+/// What's printed with `--json` once the config is written.
+#[derive(Serialize)]
+struct Written<'a> {
+    path: &'a Path,
+    config: &'a toml::Table
+}
+
+/// The values in the current config, to pre-select.
+#[derive(Debug, Clone, Default)]
+struct Current {
+    audio_device_name: Option<String>,
+    serial_port: Option<String>,
+    restore_default_source: bool,
+    serial: SerialSettings
+}
+
+impl From<Option<mewture_shared::Config>> for Current {
+    fn from(config: Option<mewture_shared::Config>) -> Self {
+        match config {
+            Some(config) => Self {
+                audio_device_name: Some(config.audio_device_name),
+                // Where the button is now, if it can be found by its USB identity.
+                serial_port: config.device.as_ref()
+                    .and_then(|device| device.resolve())
+                    .or(Some(config.serial_port)),
+                restore_default_source: config.restore_default_source,
+                serial: config.serial
+            },
+            None => Self::default()
+        }
+    }
 }
 
 fn main() {
-    // Create the ~/.mewture directory if it doesn't exist, and create the config file path.
-    let file_name = match home::home_dir() {
-        Some(path) => {
-            match fs::create_dir_all(path.join(".mewture")) {
-                Ok(_) => path.join(".mewture/config.toml"),
-                Err(e) => {
-                    eprintln!("Failed to create directory: {:?}", e);
-                    exit(1);
-                }
-            }
+    let cli = Cli::parse();
+
+    if let Err(e) = run(&cli) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(cli: &Cli) -> Result<(), String> {
+    // Find the config file path, its directory is created when it's written.
+    let file_name = mewture_shared::config_path(cli.config.as_deref())?;
+
+    if cli.migrate {
+        return migrate(&file_name);
+    }
+
+    // Start from what's there, so nothing but what's picked changes. A config that can't be used
+    // is only reported here, setup is how it gets fixed.
+    let table = mewture_shared::load_config_table(&file_name);
+    let config = match &table {
+        Ok(_) => match mewture_shared::load_config(&file_name) {
+            Ok(config) => Some(config),
+            Err(e) if file_name.exists() => {
+                eprintln!("The config in {} has problems, starting from the defaults: {}", file_name.display(), e);
+                None
+            },
+            Err(_) => None
         },
-        None => {
-            eprintln!("Failed to get home directory");
-            exit(1);
+        Err(e) => {
+            eprintln!("The config in {} can't be read: {}", file_name.display(), e);
+            None
         }
     };
+    // List and test against the same PulseAudio server the daemon will use.
+    if let Some(config) = &config {
+        config.pulseaudio.export();
+    }
+    let current = Current::from(config);
+    let serial_settings = current.serial.clone();
+    // Keep stderr clean for whoever reads our output.
+    let quiet = cli.json || cli.yes;
 
-    let mut audio_options: Vec<AudioItem> = vec![];
-    let mut serial_options: Vec<SerialPortItem> = vec![];
+    if cli.list_sources || cli.list_buttons {
+        let listing = Listing {
+            sources: match cli.list_sources {
+                true => Some(search_audio_sources(&PulseAudio::connect(Some("Mewture Button Setup")), quiet)?),
+                false => None
+            },
+            buttons: match cli.list_buttons {
+                true => Some(search_buttons(&serial_settings, quiet)?),
+                false => None
+            }
+        };
+        print_listing(&listing, cli.json);
+        return Ok(());
+    }
 
-    // Create a new spinner.
-    let pb: ProgressBar = ProgressBar::new_spinner();
-    pb.set_message("Searching for audio devices...");
-    let style: ProgressStyle = ProgressStyle::default_spinner()
-        .tick_chars("|/-\\-")
-        .template("{spinner:.green} {msg}")
-        .unwrap(); // unwraps the Result container to give the actual type.
-    pb.set_style(style);
+    let interactive = !cli.yes;
 
-    // Start the spinner.
-    pb.enable_steady_tick(Duration::from_millis(100));
+    if cli.test {
+        let (source, port) = match (&current.audio_device_name, &current.serial_port) {
+            (Some(source), Some(port)) => (source, port),
+            _ => return Err(format!("No config to test at {}, run mewture_setup first", file_name.display()))
+        };
+        let pa: PulseAudio = PulseAudio::connect(Some("Mewture Button Setup"));
+        return match verify::run(&pa, source, port, &serial_settings)? {
+            true => Ok(()),
+            false => Err("The test failed".to_string())
+        };
+    }
+
+    let mut table = match table {
+        Ok(table) => table,
+        Err(_) => start_over(&file_name, interactive)?
+    };
+
+    if cli.edit {
+        if !interactive {
+            return Err("--edit can't be used with --yes".to_string());
+        }
+        return edit(&file_name, table, &current, &serial_settings, cli.service);
+    }
 
-    // Get a list of available audio devices.
     let pa: PulseAudio = PulseAudio::connect(Some("Mewture Button Setup"));
-    let devices: Vec<pulser::api::PASourceInfo> = match pa.get_source_info_list() {
-        Ok(d) => d,
-        Err(e) => {
-            pb.finish_and_clear();
-            eprintln!("Failed to get audio devices: {}", e);
-            exit(1);
+    let audio_source = match pick_source(&pa, cli.source.as_deref(), &current, interactive, quiet)? {
+        Some(source) => source,
+        None => {
+            println!("Program exited");
+            return Ok(());
         }
     };
 
-    for dev in devices {
-        if dev.ports.len() == 0 {
-            // No input ports on the device, so let's skip it.
-            continue;
+    let button = match pick_button(cli.port.as_deref(), &current, &serial_settings, interactive, quiet)? {
+        Some(button) => button,
+        None => {
+            println!("Program exited");
+            return Ok(());
         }
+    };
 
-        let mut found: bool = false;
-        for port in dev.ports {
-            if
-                port.available == pa_port_available_t::Unknown ||
-                port.available == pa_port_available_t::Yes
-            {
-                found = true;
-                break;
+    // Catch a wrong choice now rather than once the daemon runs.
+    if interactive && prompt_yes_no("Test the button with this source before saving?", true)? {
+        let passed = verify::run(&pa, &audio_source.name, &button.port, &serial_settings)?;
+        if !passed && !prompt_yes_no("The test failed, save anyway?", false)? {
+            println!("Program exited");
+            return Ok(());
+        }
+    }
+
+    set_source(&mut table, &audio_source);
+    set_button(&mut table, &button)?;
+    mewture_shared::save_config_table(&file_name, &table)?;
+
+    if cli.json {
+        let written = Written { path: &file_name, config: &table };
+        println!("{}", serde_json::to_string_pretty(&written).unwrap());
+    } else {
+        println!("Config written to {}", file_name.display());
+    }
+
+    start_service(&file_name, &button.port, cli.service, interactive)
+}
+
+/// Offers to (re)start the daemon with the new config, see `service::install`.
+fn start_service(config_path: &Path, port_path: &str, service: bool, interactive: bool) -> Result<(), String> {
+    let wanted = service || (interactive && prompt_yes_no("Enable and (re)start mewture_daemon now?", true)?);
+    if !wanted {
+        return Ok(());
+    }
+
+    match service::install(config_path, port_path) {
+        true => Ok(()),
+        false => Err("Could not get mewture_daemon running, see above".to_string())
+    }
+}
+
+/// Lets the user change one setting at a time, starting from the current config.
+fn edit(
+    file_name: &Path,
+    mut table: toml::Table,
+    current: &Current,
+    serial_settings: &SerialSettings,
+    service: bool
+) -> Result<(), String> {
+    let pa: PulseAudio = PulseAudio::connect(Some("Mewture Button Setup"));
+    let mut current = current.clone();
+    let mut restore_default_source = current.restore_default_source;
+
+    loop {
+        let items = vec![
+            format!("Audio source: {}", current.audio_device_name.as_deref().unwrap_or("(not set)")),
+            format!("Button: {}", current.serial_port.as_deref().unwrap_or("(not set)")),
+            format!("Restore the default source on exit: {}", if restore_default_source { "yes" } else { "no" }),
+            "Save and exit".to_string(),
+            "Exit without saving".to_string()
+        ];
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Edit the config")
+            .items(&items)
+            .default(0)
+            .interact()
+            .map_err(|e| format!("Failed to prompt: {}", e))?;
+
+        match selection {
+            0 => if let Some(source) = pick_source(&pa, None, &current, true, false)? {
+                current.audio_device_name = Some(source.name.clone());
+                set_source(&mut table, &source);
+            },
+            1 => if let Some(button) = pick_button(None, &current, serial_settings, true, false)? {
+                current.serial_port = Some(button.port.clone());
+                set_button(&mut table, &button)?;
+            },
+            2 => {
+                restore_default_source = !restore_default_source;
+                table.insert("restore_default_source".to_string(), toml::Value::Boolean(restore_default_source));
+            },
+            3 => {
+                mewture_shared::save_config_table(file_name, &table)?;
+                println!("Config written to {}", file_name.display());
+                return match &current.serial_port {
+                    Some(port_path) => start_service(file_name, port_path, service, true),
+                    None => Ok(())
+                };
+            },
+            _ => {
+                println!("Program exited");
+                return Ok(());
             }
         }
+    }
+}
 
-        if found {
-            audio_options.push(
-                AudioItem {
-                    value: Some(dev.name.unwrap()),
-                    display_text: dev.description.unwrap()
-                }
-            );
+/// Picks the audio source from `spec` if given, otherwise lets the user choose (or takes the
+/// current or only one when not `interactive`).
+fn pick_source(
+    pa: &PulseAudio,
+    spec: Option<&str>,
+    current: &Current,
+    interactive: bool,
+    quiet: bool
+) -> Result<Option<AudioSource>, String> {
+    let audio_options = search_audio_sources(pa, quiet)?;
+    if let Some(spec) = spec {
+        return match_source(&audio_options, spec).map(|source| Some(source.clone()));
+    }
+
+    let selected = audio_options.iter()
+        .position(|source| Some(&source.name) == current.audio_device_name.as_ref());
+    choose(
+        "Select an audio device",
+        &audio_options,
+        |source| source.description.clone(),
+        selected,
+        interactive
+    )
+        .map(|source| source.cloned())
+        .map_err(|e| format!("{} audio sources, pick one with --source", e))
+}
+
+/// Uses the port given, or finds the buttons and lets the user choose (or takes the current or
+/// only one when not `interactive` or `port` is `auto`).
+fn pick_button(
+    port: Option<&str>,
+    current: &Current,
+    serial_settings: &SerialSettings,
+    interactive: bool,
+    quiet: bool
+) -> Result<Option<Button>, String> {
+    if let Some(port) = port.filter(|port| *port != "auto") {
+        let button = discovery::usb_ports()?
+            .iter()
+            .find(|info| same_file(&info.port_name, port))
+            .map(|info| Button { port: port.to_string(), device: discovery::button_at(info).device })
+            .unwrap_or_else(|| Button { port: port.to_string(), device: None });
+        return Ok(Some(button));
+    }
+
+    let serial_options = search_buttons(serial_settings, quiet)?;
+    if serial_options.is_empty() && port.is_none() && interactive {
+        println!("No buttons found on USB serial ports");
+        return Ok(None);
+    }
+
+    let selected = serial_options.iter()
+        .position(|button| current.serial_port.as_deref().is_some_and(|current| same_file(&button.port, current)));
+    choose(
+        "Select a serial port",
+        &serial_options,
+        |button| button.port.clone(),
+        selected,
+        interactive && port.is_none()
+    )
+        .map(|button| button.cloned())
+        .map_err(|e| format!("{} buttons, pick one with --port", e))
+}
+
+fn set_source(table: &mut toml::Table, source: &AudioSource) {
+    table.insert("version".to_string(), toml::Value::Integer(i64::from(mewture_shared::CONFIG_VERSION)));
+    table.insert("audio_device_name".to_string(), toml::Value::String(source.name.clone()));
+}
+
+fn set_button(table: &mut toml::Table, button: &Button) -> Result<(), String> {
+    table.insert("version".to_string(), toml::Value::Integer(i64::from(mewture_shared::CONFIG_VERSION)));
+    table.insert("serial_port".to_string(), toml::Value::String(button.port.clone()));
+    match &button.device {
+        Some(device) => {
+            let device = toml::Value::try_from(device).map_err(|e| format!("Unable to serialize device: {}", e))?;
+            table.insert("device".to_string(), device);
+        },
+        None => {
+            table.remove("device");
         }
     }
 
-    audio_options.push(AudioItem { value: None, display_text: "Cancel".to_string() });
-    pb.finish_and_clear();
+    Ok(())
+}
+
+/// Upgrades the config file to the current version, see `mewture_shared::migrate_config`.
+fn migrate(file_name: &Path) -> Result<(), String> {
+    match mewture_shared::migrate_config(file_name) {
+        Ok(Some(backup)) => println!(
+            "Migrated {} to config version {}, the old file was saved as {}",
+            file_name.display(),
+            mewture_shared::CONFIG_VERSION,
+            backup.display()
+        ),
+        Ok(None) => println!(
+            "{} needs no changes, it's read as config version {} as it is",
+            file_name.display(),
+            mewture_shared::CONFIG_VERSION
+        ),
+        Err(e) => return Err(format!("Failed to migrate {}: {}", file_name.display(), e))
+    }
+
+    Ok(())
+}
+
+/// Moves a config that can't be read out of the way, if the user agrees, so a new one can be
+/// written in its place. Returns the empty table to start from.
+fn start_over(file_name: &Path, interactive: bool) -> Result<toml::Table, String> {
+    if !interactive || !prompt_yes_no("Put it aside and start over?", false)? {
+        return Err(format!("Fix or move {} first", file_name.display()));
+    }
+
+    let backup = mewture_shared::backup_path(file_name, "broken");
+    fs::rename(file_name, &backup)
+        .map_err(|e| format!("Could not move {} to {}: {}", file_name.display(), backup.display(), e))?;
+    println!("Moved {} to {}", file_name.display(), backup.display());
+
+    Ok(toml::Table::new())
+}
+
+/// Creates a spinner showing `message`, or a hidden one when `quiet`.
+fn spinner(message: &'static str, quiet: bool) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
 
-    // Get a list of available serial ports.
     let pb: ProgressBar = ProgressBar::new_spinner();
-    pb.set_message("Searching for serial devices...");
-    let style = ProgressStyle::default_spinner()
+    pb.set_message(message);
+    let style: ProgressStyle = ProgressStyle::default_spinner()
         .tick_chars("|/-\\-")
         .template("{spinner:.green} {msg}")
-        .unwrap();
+        .unwrap(); // unwraps the Result container to give the actual type.
     pb.set_style(style);
+
+    // Start the spinner.
     pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
 
-    let ports: Vec<serialport::SerialPortInfo> =
-        serialport::available_ports().expect("Failed to enumerate serial ports");
-    let usb_ports: Vec<_> = ports
-        .into_iter()
-        .filter(|port| match port.port_type {
-            SerialPortType::UsbPort(_) => {
-                true
-            },
-            _ => false,
-        })
-        .collect();
-    if usb_ports.is_empty() {
-        println!("No USB serial ports found");
+fn search_audio_sources(pa: &PulseAudio, quiet: bool) -> Result<Vec<AudioSource>, String> {
+    let pb = spinner("Searching for audio devices...", quiet);
+    let sources = discovery::find_audio_sources(pa);
+    pb.finish_and_clear();
+    sources
+}
+
+fn search_buttons(serial_settings: &SerialSettings, quiet: bool) -> Result<Vec<Button>, String> {
+    let pb = spinner("Searching for serial devices...", quiet);
+    let buttons = discovery::find_buttons(serial_settings);
+    pb.finish_and_clear();
+    buttons
+}
+
+fn print_listing(listing: &Listing, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(listing).unwrap());
         return;
-    } else {
-        for port in usb_ports {
-            let port_name: String = match port.port_type {
-                SerialPortType::UsbPort(p) => {
-                     match p.serial_number {
-                        Some(sn) => {
-                            match glob(&format!("/dev/serial/by-id/*{}*", sn))
-                                .expect("Glob error")
-                                .next() {
-                                Some(path) => {
-                                   match path {
-                                        Ok(p) => {
-                                            // We have a matching serial number, return it.
-                                            p.display().to_string()
-                                        },
-                                        _ => {
-                                            // No matching directory, return the port name.
-                                            port.port_name.clone()
-                                        }
-                                    }
-                                },
-                                None => {
-                                    // No matching directory, return the port name.
-                                    port.port_name.clone()
-                                }
-                            }
-                        },
-                        None => {
-                            // No serial number, just use it's port name.
-                            port.port_name.clone()
-                        }
-                    }
-                },
-                _ => {
-                    continue;
-                }
-            };
-
-            let serial_port = serialport::new(port_name, 115200)
-                .timeout(Duration::from_secs(6))
-                .open();
-
-            match serial_port {
-                Ok(mut port) => {
-                    let mut received_buffer: Vec<u8> = vec![0; 64];
-                    let mut read_attempts = 0;
-
-                    loop {
-                        if read_attempts >= 5 {
-                            // Maximum read attempts reached, break the loop.
-                            eprintln!("Exceeded maximum read attempts");
-                            break;
-                        }
-
-                        let bytes_read = match port.read(&mut received_buffer) {
-                            Ok(bytes_read) => bytes_read,
-                            Err(_error) => {
-                                read_attempts += 1;
-                                continue;
-                            }
-                        };
-
-                        if bytes_read > 7 {
-                            // Parse the received data
-                            let message = ddaa_protocol::parse_protocol_message(
-                                &mut received_buffer
-                            );
-                            if let Some(parsed_message) = message {
-                                if parsed_message.command == ddaa_protocol::Command::Ping {
-                                    serial_options.push
-                                    (
-                                        SerialPortItem {
-                                            value: Some(port.name().unwrap()),
-                                            display_text: port.name().unwrap()
-                                        }
-                                    );
-                                    break;
-                                }
-                            }
-
-                            read_attempts += 1;
-                        }
-                    }
-                },
-                Err(error) => {
-                    eprintln!("Error reading from serial port: {}", error);
-                    continue; // Continue to the next iteration of the loop
-                }
-            };
+    }
+
+    if let Some(sources) = &listing.sources {
+        println!("Audio sources:");
+        for (index, source) in sources.iter().enumerate() {
+            println!("  {}: {} ({})", index, source.name, source.description);
         }
     }
-    serial_options.push(SerialPortItem { value: None, display_text: "Cancel".to_string() });
+    if let Some(buttons) = &listing.buttons {
+        println!("Buttons:");
+        for button in buttons {
+            match &button.device {
+                Some(device) => println!("  {} ({})", button.port, device),
+                None => println!("  {}", button.port)
+            }
+        }
+    }
+}
 
-    pb.finish_and_clear();
+/// Finds the one source `spec` means: an exact name, an index into the list, or a pattern
+/// matching the name or description.
+fn match_source<'a>(sources: &'a [AudioSource], spec: &str) -> Result<&'a AudioSource, String> {
+    if let Some(source) = sources.iter().find(|source| source.name == spec) {
+        return Ok(source);
+    }
+    if let Ok(index) = spec.parse::<usize>() {
+        return sources.get(index).ok_or_else(|| format!("There is no audio source {}", index));
+    }
 
-    // Create a selection prompt for the audio devices.
-    let audio_selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select an audio device")
-        .items(&audio_options.iter().map(|item| item.display_text.as_str()).collect::<Vec<_>>())
-        .interact()
-        .unwrap();
-
-    let selected_audio_item = &audio_options[audio_selection];
-    let audio_device = match selected_audio_item.value.to_owned() {
-        Some(value) => match pa.get_source_info(PAIdent::Name(value)) {
-            Ok(info) => info,
-            Err(e) => {
-                eprintln!("Failed to get audio device: {}", e);
-                exit(1);
-            }
-        },
-        None => {
-            println!("Program exited");
-            return;
+    let pattern = glob::Pattern::new(spec).map_err(|e| format!("Invalid pattern `{}`: {}", spec, e))?;
+    let matching: Vec<&AudioSource> = sources.iter()
+        .filter(|source| pattern.matches(&source.name) || pattern.matches(&source.description))
+        .collect();
+    match matching.as_slice() {
+        [source] => Ok(source),
+        [] => Err(format!("No audio source matches `{}`", spec)),
+        _ => Err(format!(
+            "Several audio sources match `{}`:\n  {}",
+            spec,
+            matching.iter().map(|source| source.name.as_str()).collect::<Vec<_>>().join("\n  ")
+        ))
+    }
+}
+
+/// Lets the user pick one of `items`, starting at `selected`. When not `interactive` `selected`
+/// is taken, or the only item there is.
+/// Returns `None` if the user cancelled. The error says how many there were (`Found no`,
+/// `Found 3`), for the caller to finish the sentence.
+fn choose<'a, T>(
+    prompt: &str,
+    items: &'a [T],
+    label: impl Fn(&T) -> String,
+    selected: Option<usize>,
+    interactive: bool
+) -> Result<Option<&'a T>, String> {
+    if !interactive {
+        if let Some(selected) = selected {
+            return Ok(items.get(selected));
         }
-    };
+        return match items {
+            [item] => Ok(Some(item)),
+            [] => Err("Found no".to_string()),
+            _ => Err(format!("Found {}", items.len()))
+        };
+    }
 
-    // Create a selection prompt for the serial ports.
-    let serial_selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("Select a serial port")
-        .items(&serial_options.iter().map(|item| item.display_text.as_str()).collect::<Vec<_>>())
+    let mut labels: Vec<String> = items.iter().map(label).collect();
+    labels.push("Cancel".to_string());
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .items(&labels)
+        .default(selected.unwrap_or(0))
         .interact()
-        .unwrap();
+        .map_err(|e| format!("Failed to prompt: {}", e))?;
 
-    let selected_serial_item = &serial_options[serial_selection];
-    let serial = match &selected_serial_item.value {
-        Some(serial) => serial,
-        None => {
-            println!("Program exited");
-            return;
-        }
-    };
+    Ok(items.get(selection))
+}
 
-    // Create the content for the config file.
-    let config = mewture_shared::Config {
-        audio_device_name: audio_device.name.unwrap(),
-        serial_port: serial.to_string(),
-    };
-    let toml = toml::to_string(&config).unwrap();
+fn prompt_yes_no(prompt: &str, default: bool) -> Result<bool, String> {
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .default(default)
+        .interact()
+        .map_err(|e| format!("Failed to prompt: {}", e))
+}
 
-    // Write the config file.
-    let mut file = File::create(file_name).expect("Could not open file.");
-    file.write_all(toml.as_bytes()).expect("Could not write TOML config.");
+/// Whether both paths lead to the same file, e.g. a `/dev/serial/by-id/...` link and its tty.
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b
+    }
 }
@@ -0,0 +1,192 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The daemon's systemd user unit.
+const UNIT: &str = "mewture_daemon.service";
+
+/// The unit the package installs, written to the user's unit directory when it isn't installed.
+const UNIT_TEMPLATE: &str = include_str!("../../debian/mewture_daemon.service");
+
+/// How long to watch the daemon start before reporting how far it got.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gets the daemon running for the current user with the config just written to `config_path`:
+/// checks the user may open the button's port, installs the user unit if there is none, points
+/// it at the config, enables it and (re)starts it. Each step is reported as it's done, on stderr.
+/// Returns whether every step succeeded.
+pub fn install(config_path: &Path, port_path: &str) -> bool {
+    let mut ok = true;
+
+    ok &= report("Serial port permissions", check_port_access(port_path));
+
+    let unit = report("User unit installed", ensure_unit());
+    ok &= unit;
+    if !unit {
+        return false;
+    }
+
+    let config = report("Config used", set_config(config_path));
+    ok &= config;
+    if !config {
+        return false;
+    }
+
+    ok &= report("Enabled on login", systemctl(&["enable", UNIT]).map(|_| UNIT.to_string()));
+    ok &= report("Daemon restarted", restart());
+
+    ok
+}
+
+fn report(step: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            eprintln!("  [ok] {}: {}", step, detail);
+            true
+        },
+        Err(e) => {
+            eprintln!("  [failed] {}: {}", step, e);
+            false
+        }
+    }
+}
+
+/// Whether we may open the port, and what to do about it if not (usually joining `dialout`).
+fn check_port_access(port_path: &str) -> Result<String, String> {
+    match mewture_shared::diagnose_port_access(port_path) {
+        Some(problem) => Err(problem),
+        None => Ok(format!("{} can be opened", port_path))
+    }
+}
+
+/// Makes sure systemd knows the unit, writing it to the user's unit directory when it doesn't
+/// (e.g. when installed with `cargo install` rather than the package).
+fn ensure_unit() -> Result<String, String> {
+    if systemctl(&["cat", UNIT]).is_ok() {
+        return Ok(format!("{} is installed", UNIT));
+    }
+
+    // The daemon is expected next to us.
+    let daemon = env::current_exe()
+        .map_err(|e| format!("Could not find mewture_setup's path: {}", e))?
+        .with_file_name("mewture_daemon");
+    if !daemon.exists() {
+        return Err(format!("{} doesn't exist", daemon.display()));
+    }
+
+    let unit = UNIT_TEMPLATE.replace(
+        "ExecStart=/usr/local/bin/mewture_daemon",
+        &format!("ExecStart={}", daemon.display())
+    );
+    let path = unit_dir()?.join(UNIT);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    mewture_shared::write_atomically(&path, &unit)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    systemctl(&["daemon-reload"])?;
+
+    Ok(format!("wrote {}", path.display()))
+}
+
+/// Has the unit read `config_path`, through a drop-in setting `MEWTURE_CONFIG`: the user's
+/// manager doesn't get `--config` or the shell's environment, and the packaged unit can't be
+/// edited.
+fn set_config(config_path: &Path) -> Result<String, String> {
+    let config_path = match config_path.is_absolute() {
+        true => config_path.to_path_buf(),
+        false => env::current_dir()
+            .map_err(|e| format!("Could not find the current directory: {}", e))?
+            .join(config_path)
+    };
+    let config_path = config_path.to_str()
+        .ok_or_else(|| format!("{} isn't valid UTF-8, systemd can't be given it", config_path.display()))?;
+
+    // Quoted, so spaces are kept, with what systemd would expand escaped.
+    let value = format!("{}={}", mewture_shared::CONFIG_ENV, config_path)
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    let drop_in = format!("[Service]\nEnvironment=\"{}\"\n", value);
+
+    let path = unit_dir()?.join(format!("{}.d/config.conf", UNIT));
+    if fs::read_to_string(&path).ok().as_deref() == Some(drop_in.as_str()) {
+        return Ok(format!("{} already uses {}", UNIT, config_path));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    mewture_shared::write_atomically(&path, &drop_in)
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    systemctl(&["daemon-reload"])?;
+
+    Ok(format!("wrote {} for {}", path.display(), config_path))
+}
+
+/// Restarts the daemon without waiting on it, so a stop that hangs can't hold setup up. Watches
+/// it for a little while instead, to tell whether it started, is still starting, or failed.
+fn restart() -> Result<String, String> {
+    systemctl(&["--no-block", "restart", UNIT])?;
+
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        let state = systemctl(&[
+            "show",
+            "--property=ActiveState",
+            "--property=SubState",
+            "--property=Job",
+            "--property=StatusText",
+            UNIT
+        ])?;
+        let property = |name: &str| state.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or("")
+            .to_string();
+        let (active_state, sub_state, job) = (property("ActiveState"), property("SubState"), property("Job"));
+
+        // With a job still queued or running, the state may be the old daemon's or a start in progress.
+        if job.is_empty() {
+            return match active_state.as_str() {
+                "active" => Ok(format!("{} is running: {}", UNIT, property("StatusText"))),
+                _ => Err(format!(
+                    "{} is {} ({}), see `journalctl --user -u {}`",
+                    UNIT,
+                    active_state,
+                    sub_state,
+                    UNIT
+                ))
+            };
+        }
+        if Instant::now() >= deadline {
+            return Ok(format!("{} is {} ({}), still starting", UNIT, active_state, sub_state));
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// `$XDG_CONFIG_HOME/systemd/user`, where user units go.
+fn unit_dir() -> Result<PathBuf, String> {
+    Ok(mewture_shared::config_home()?.join("systemd/user"))
+}
+
+/// Runs `systemctl --user` with `args`, returning its output, or its error output if it fails.
+fn systemctl(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Could not run systemctl: {}", e))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "systemctl --user {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
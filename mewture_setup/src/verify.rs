@@ -0,0 +1,188 @@
+use ddaa_protocol::{Command, MessageType, ProtocolMessage};
+use mewture_shared::SerialSettings;
+use pulser::api::PAIdent;
+use pulser::simple::PulseAudio;
+use serialport::SerialPort;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// DDAA variable holding the source's mute state, same as the daemon's.
+const MUTE_VARIABLE: u8 = 0x00;
+
+/// How long the button gets to acknowledge a write.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the user gets to press the button.
+const PRESS_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a single read waits, so deadlines are noticed.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Checks the chosen source and button work together, the way the daemon will use them:
+/// mutes the source from here and sees the button acknowledge (and the user sees the LED
+/// follow), then has the user press the button and sees the source follow.
+/// The source's and the button's mute state are put back afterwards, however the test ended.
+/// Returns whether every step passed. Not being able to open the port (the daemon may hold it)
+/// counts as a failed test.
+///
+/// # Errors
+///
+/// Returns an error if the source can't be used at all, or the port can't be set up once open.
+pub fn run(pa: &PulseAudio, source: &str, port_path: &str, settings: &SerialSettings) -> Result<bool, String> {
+    let mut port = match settings.builder(port_path)?.timeout(READ_TIMEOUT).open() {
+        Ok(port) => port,
+        Err(e) => {
+            println!(
+                "Could not open {}: {} (if mewture_daemon is running, stop it first: systemctl --user stop mewture_daemon)",
+                port_path,
+                e
+            );
+            return Ok(report(false, "The port opened"));
+        }
+    };
+    settings.apply_control_lines(port.as_mut())
+        .map_err(|e| format!("Error setting DTR/RTS on serial port: {}", e))?;
+
+    let original = get_mute(pa, source)?;
+    let result = exercise(pa, source, port.as_mut(), !original);
+
+    // Leave things as we found them, each on its own so one failing doesn't keep the other back.
+    let restored = [
+        set_mute(pa, source, original),
+        send_mute_state(port.as_mut(), original)
+    ];
+    for e in restored.iter().filter_map(|result| result.as_ref().err()) {
+        println!("{}", e);
+    }
+    let restored = report(
+        restored.iter().all(Result::is_ok),
+        &format!("{} and the button are {} again", source, if original { "muted" } else { "unmuted" })
+    );
+
+    Ok(result? && restored)
+}
+
+/// The steps of `run`, starting with the source's mute state set to `target`.
+fn exercise(pa: &PulseAudio, source: &str, port: &mut dyn SerialPort, target: bool) -> Result<bool, String> {
+    let mut passed = true;
+
+    // Host to button.
+    println!("{} {} from here...", if target { "Muting" } else { "Unmuting" }, source);
+    set_mute(pa, source, target)?;
+    send_mute_state(port, target)?;
+    let acknowledged = wait_for(port, target, RESPONSE_TIMEOUT, |message| {
+        message.command == Command::Write
+            && message.variable == MUTE_VARIABLE
+            && message.message_type != MessageType::Request
+    })?;
+    passed &= report(
+        matches!(acknowledged, Some(ProtocolMessage { message_type: MessageType::ResponseSuccess, .. })),
+        "The button acknowledged the new mute state"
+    );
+    passed &= report(
+        crate::prompt_yes_no(&format!("Does the button's LED show {}?", if target { "muted" } else { "unmuted" }), true)?,
+        "The LED followed"
+    );
+
+    // Button to host.
+    println!("Press the button...");
+    let pressed = wait_for(port, target, PRESS_TIMEOUT, |message| {
+        message.command == Command::Write
+            && message.variable == MUTE_VARIABLE
+            && message.message_type == MessageType::Request
+    })?;
+    let followed = match pressed {
+        Some(message) => {
+            // Do what the daemon would: apply it, acknowledge it and echo the new state back.
+            let wanted = match message.data.first() {
+                Some(0x00) => Some(false),
+                Some(0x01) => Some(true),
+                Some(0x02) => Some(!target),
+                _ => None
+            };
+            match wanted {
+                Some(wanted) => {
+                    set_mute(pa, source, wanted)?;
+                    send(port, MessageType::ResponseSuccess, message.command, message.variable, &message.data)?;
+                    send_mute_state(port, wanted)?;
+                    get_mute(pa, source)? == wanted
+                },
+                None => {
+                    send(port, MessageType::ResponseError, message.command, message.variable, &message.data)?;
+                    false
+                }
+            }
+        },
+        None => false
+    };
+    passed &= report(followed, &format!("{} followed the button", source));
+
+    Ok(passed)
+}
+
+fn report(ok: bool, what: &str) -> bool {
+    println!("  [{}] {}", if ok { "ok" } else { "failed" }, what);
+    ok
+}
+
+fn get_mute(pa: &PulseAudio, source: &str) -> Result<bool, String> {
+    pa.get_source_mute(PAIdent::Name(source.to_string()))
+        .map_err(|e| format!("Failed to get the mute state of {}: {}", source, e))
+}
+
+fn set_mute(pa: &PulseAudio, source: &str, mute_state: bool) -> Result<(), String> {
+    pa.set_source_mute(PAIdent::Name(source.to_string()), mute_state)
+        .map_err(|e| format!("Failed to set the mute state of {}: {}", source, e))
+}
+
+fn send_mute_state(port: &mut dyn SerialPort, mute_state: bool) -> Result<(), String> {
+    send(port, MessageType::Request, Command::Write, MUTE_VARIABLE, &[u8::from(mute_state)])
+}
+
+fn send(port: &mut dyn SerialPort, message_type: MessageType, command: Command, variable: u8, data: &[u8]) -> Result<(), String> {
+    let buffer = ddaa_protocol::create_protocol_buffer(message_type, command, variable, data);
+    port.write_all(&buffer).map_err(|e| format!("Error writing to serial port: {}", e))
+}
+
+/// Reads messages until one matches `wanted` or `timeout` runs out. Pings and reads of the mute
+/// state (`mute_state`) are answered meanwhile, as the daemon would.
+fn wait_for(
+    port: &mut dyn SerialPort,
+    mute_state: bool,
+    timeout: Duration,
+    wanted: impl Fn(&ProtocolMessage) -> bool
+) -> Result<Option<ProtocolMessage>, String> {
+    let deadline = Instant::now() + timeout;
+    let mut received_buffer: Vec<u8> = vec![0; 64];
+
+    while Instant::now() < deadline {
+        let bytes_read = match port.read(&mut received_buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(format!("Error reading from serial port: {}", e))
+        };
+        if bytes_read <= 7 {
+            continue;
+        }
+
+        let message = match ddaa_protocol::parse_protocol_message(&mut received_buffer) {
+            Some(message) => message,
+            None => continue
+        };
+        if wanted(&message) {
+            return Ok(Some(message));
+        }
+
+        if message.message_type == MessageType::Request {
+            match message.command {
+                Command::Ping => send(port, MessageType::ResponseSuccess, Command::Ping, message.variable, &message.data)?,
+                Command::Read if message.variable == MUTE_VARIABLE => {
+                    send(port, MessageType::ResponseSuccess, Command::Read, MUTE_VARIABLE, &[u8::from(mute_state)])?
+                },
+                _ => {}
+            }
+        }
+    }
+
+    Ok(None)
+}
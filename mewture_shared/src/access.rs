@@ -0,0 +1,151 @@
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+/// Why this process can't open the serial port at `path` for reading and writing, and what to do
+/// about it (usually joining the group that owns it), or `None` if it can.
+pub fn diagnose_port_access(path: &str) -> Option<String> {
+    if can_read_write(path) {
+        return None;
+    }
+
+    let gid = match fs::metadata(path) {
+        Ok(metadata) => metadata.gid(),
+        Err(e) => return Some(format!("`{}` can't be found: {}", path, e))
+    };
+
+    // Adding people to root's group is not the fix.
+    if gid == 0 {
+        return Some(format!("No permission to open `{}`, it's only accessible to root.", path));
+    }
+
+    let group = group_name(gid).unwrap_or_else(|| gid.to_string());
+    let user = env::var("USER").unwrap_or_else(|_| "$USER".to_string());
+    if user_in_group(&user, gid) {
+        Some(format!(
+            "No permission to open `{}`. You're in the `{}` group, but not in this session yet: log out and back in.",
+            path,
+            group
+        ))
+    } else {
+        Some(format!(
+            "No permission to open `{}`. Add yourself to the `{}` group (`sudo usermod -aG {} {}`), then log out and back in.",
+            path,
+            group,
+            group,
+            user
+        ))
+    }
+}
+
+/// Whether this process may open `path` for reading and writing.
+fn can_read_write(path: &str) -> bool {
+    match CString::new(path) {
+        // SAFETY: `path` is a valid NUL terminated string for the duration of the call.
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) == 0 },
+        Err(_) => false
+    }
+}
+
+/// Looks up a group's name through NSS, like `user_in_group`.
+fn group_name(gid: libc::gid_t) -> Option<String> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: `group` is plain data, all zeroes is a valid (empty) value for it.
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call and `buffer.len()` is the buffer's size.
+        let error = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match error {
+            // SAFETY: on success `gr_name` points to a NUL terminated string in `buffer`.
+            0 if !result.is_null() => {
+                return Some(unsafe { CStr::from_ptr(group.gr_name) }.to_string_lossy().to_string());
+            },
+            libc::ERANGE => {
+                let size = buffer.len() * 2;
+                buffer.resize(size, 0);
+            },
+            _ => return None
+        }
+    }
+}
+
+/// Whether `user` is in the group `gid`, either as its primary group or as a member. Goes
+/// through NSS like `id` does, so groups from LDAP or sssd count too.
+fn user_in_group(user: &str, gid: u32) -> bool {
+    let user = match CString::new(user) {
+        Ok(user) => user,
+        Err(_) => return false
+    };
+    let primary_gid = match primary_gid(&user) {
+        Some(primary_gid) => primary_gid,
+        None => return false
+    };
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = libc::c_int::try_from(groups.len()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `user` is NUL terminated and `groups` has room for `count` gids.
+        let found = unsafe { libc::getgrouplist(user.as_ptr(), primary_gid, groups.as_mut_ptr(), &mut count) };
+        match usize::try_from(found) {
+            Ok(found) => return groups[..found].contains(&gid),
+            // Too many groups, `count` now says how many there are.
+            Err(_) => {
+                let needed = usize::try_from(count).unwrap_or(0).max(groups.len() * 2);
+                groups.resize(needed, 0);
+            }
+        }
+    }
+}
+
+/// The primary group of `user`, from its passwd entry.
+fn primary_gid(user: &CStr) -> Option<libc::gid_t> {
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: `passwd` is plain data, all zeroes is a valid (empty) value for it.
+        let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the call and `buffer.len()` is the buffer's size.
+        let error = unsafe {
+            libc::getpwnam_r(user.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result)
+        };
+        match error {
+            0 if !result.is_null() => return Some(passwd.pw_gid),
+            libc::ERANGE => {
+                let size = buffer.len() * 2;
+                buffer.resize(size, 0);
+            },
+            _ => return None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_primary_group() {
+        // root's primary group is root, and it's rarely listed as a member of it.
+        assert!(user_in_group("root", 0));
+    }
+
+    #[test]
+    fn names_groups() {
+        assert_eq!(group_name(0).as_deref(), Some("root"));
+        assert_eq!(group_name(libc::gid_t::MAX - 1), None);
+    }
+
+    #[test]
+    fn unknown_users_are_in_no_group() {
+        assert!(!user_in_group("no-such-mewture-user", 0));
+        assert!(!user_in_group("nul\0user", 0));
+    }
+
+    #[test]
+    fn missing_ports_are_reported() {
+        let problem = diagnose_port_access("/dev/no-such-mewture-port").unwrap();
+        assert!(problem.starts_with("`/dev/no-such-mewture-port` can't be found"), "{}", problem);
+    }
+}
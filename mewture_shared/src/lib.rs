@@ -1,8 +1,1101 @@
 use serde::{ Deserialize, Serialize };
+use serialport::{ SerialPortBuilder, SerialPortType, UsbPortInfo };
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+mod access;
+mod migration;
+mod paths;
+
+pub use access::diagnose_port_access;
+pub use migration::{ backup_path, migrate_config, upgrade, version_of };
+pub use paths::{ config_home, config_path, state_home, state_path, CONFIG_ENV, SYSTEM_CONFIG_PATH };
+
+/// The config file version this build reads and writes, see `migration`.
+pub const CONFIG_VERSION: u32 = 2;
 
 /// Configuration to store the audio device and serial port information.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Which version of this struct the file was written for, see `CONFIG_VERSION`.
+    #[serde(default = "default_version")]
+    pub version: u32,
     pub audio_device_name: String,
-    pub serial_port: String
+    /// Can be left out when the button is found through `device`, or reached through `tcp`.
+    #[serde(default)]
+    pub serial_port: String,
+    /// Put the default source back to what it was before the daemon started, when it exits.
+    #[serde(default)]
+    pub restore_default_source: bool,
+    /// How to talk to the button over the serial port.
+    #[serde(default)]
+    pub serial: SerialSettings,
+    /// Find the button by its USB identity rather than `serial_port`, when set.
+    #[serde(default)]
+    pub device: Option<DeviceSelector>,
+    /// Reach the button over TCP rather than a serial port, when set.
+    #[serde(default)]
+    pub tcp: Option<TcpSettings>,
+    /// Which PulseAudio server to control.
+    #[serde(default)]
+    pub pulseaudio: PulseAudioSettings,
+    /// Send the button a richer status than mute alone, with these values, when set.
+    #[serde(default)]
+    pub status: Option<StatusMap>,
+    /// Watch for apps recording from the source, and what to do when they start or stop.
+    #[serde(default)]
+    pub call: Option<CallSettings>,
+    /// Show a desktop notification when the mute state changes, when set.
+    #[serde(default)]
+    pub notifications: Option<NotificationSettings>,
+    /// Play a sound when the mute state changes, when set.
+    #[serde(default)]
+    pub feedback: Option<FeedbackSettings>,
+    /// Shell commands to run when things change, when set.
+    #[serde(default)]
+    pub hooks: Option<HookSettings>,
+    /// Time windows in which to mute or unmute, the `[[schedule]]` entries.
+    #[serde(default)]
+    pub schedule: Vec<ScheduleRule>,
+    /// Mute while the screen is locked, when set.
+    #[serde(default)]
+    pub lock: Option<LockSettings>,
+    /// What to do with the mute state when the daemon starts.
+    #[serde(default)]
+    pub startup: StartupSettings
+}
+
+/// The `[startup]` section of the config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StartupSettings {
+    pub mute: StartupMute
+}
+
+/// The mute state the daemon starts with, before the button is synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMute {
+    /// Leave it as the server has it.
+    #[default]
+    Keep,
+    /// Put back the state the daemon last saw, kept in `state_path()`.
+    Restore,
+    /// Always start muted.
+    Muted,
+    /// Always start unmuted.
+    Unmuted
+}
+
+/// Muting on screen lock, the `[lock]` section of the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LockSettings {
+    /// Unmute again on unlock, if locking is what muted the source.
+    pub restore: bool,
+    /// Follow logind's `LockedHint` for the user's graphical session.
+    pub logind: bool,
+    /// Follow the screensaver's `ActiveChanged` signal on the session bus.
+    pub screensaver: bool
+}
+
+impl Default for LockSettings {
+    fn default() -> Self {
+        Self { restore: false, logind: true, screensaver: true }
+    }
+}
+
+/// Names of the days, as used in `ScheduleRule::days`, Monday first.
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Mutes or unmutes between two times of day, one `[[schedule]]` entry in the config.
+/// A window whose end is before its start runs past midnight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    /// Days the window starts on ("mon" to "sun"), every day when empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Start of the window, "HH:MM" local time.
+    pub from: String,
+    /// End of the window, "HH:MM" local time.
+    pub to: String,
+    pub action: ScheduleAction,
+    #[serde(default)]
+    pub mode: ScheduleMode
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleAction {
+    Mute,
+    Unmute
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleMode {
+    /// Keep the source that way for the whole window. The button overrides it until the next
+    /// start or end of a window.
+    #[default]
+    Enforce,
+    /// Only mute or unmute when the window starts.
+    Start
+}
+
+impl ScheduleRule {
+    /// `from` as (hour, minute).
+    pub fn start(&self) -> Result<(u32, u32), String> {
+        parse_time(&self.from)
+    }
+
+    /// `to` as (hour, minute).
+    pub fn end(&self) -> Result<(u32, u32), String> {
+        parse_time(&self.to)
+    }
+
+    /// `days` as days from Monday, every day when empty.
+    pub fn weekdays(&self) -> Result<Vec<u32>, String> {
+        if self.days.is_empty() {
+            return Ok((0..7).collect());
+        }
+
+        self.days.iter()
+            .map(|day| match WEEKDAYS.iter().position(|name| name.eq_ignore_ascii_case(day)) {
+                Some(index) => Ok(index as u32),
+                None => Err(format!("Invalid schedule day `{}`, expected one of {}", day, WEEKDAYS.join(", ")))
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start()? == self.end()? {
+            return Err(format!("Schedule window {}-{} is empty", self.from, self.to));
+        }
+        self.weekdays().map(|_| ())
+    }
+}
+
+/// Parses "HH:MM" into (hour, minute).
+fn parse_time(time: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid schedule time `{}`, expected HH:MM", time);
+    let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+    let hour: u32 = hour.trim().parse().map_err(|_| invalid())?;
+    let minute: u32 = minute.trim().parse().map_err(|_| invalid())?;
+    if hour > 23 || minute > 59 {
+        return Err(invalid());
+    }
+
+    Ok((hour, minute))
+}
+
+/// Shell commands run on state changes, the `[hooks]` section of the config.
+/// They're run with `sh -c`, and get the state in `MEWTURE_*` environment variables.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HookSettings {
+    pub mute: Option<String>,
+    pub unmute: Option<String>,
+    /// The button connected.
+    pub connect: Option<String>,
+    /// The button went away.
+    pub disconnect: Option<String>,
+    /// The audio source or server went away.
+    pub audio_error: Option<String>,
+    /// How long a command may run before it's killed, in milliseconds.
+    pub timeout_ms: u64,
+    /// How many commands may run at once, more are skipped.
+    pub max_concurrent: usize
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        Self {
+            mute: None,
+            unmute: None,
+            connect: None,
+            disconnect: None,
+            audio_error: None,
+            timeout_ms: 10000,
+            max_concurrent: 4
+        }
+    }
+}
+
+impl HookSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Sounds played on mute changes, the `[feedback]` section of the config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeedbackSettings {
+    /// Sink to play on, the default sink when unset.
+    pub sink: Option<String>,
+    /// WAV file played when muting, a built-in low tone when unset.
+    pub mute_sound: Option<PathBuf>,
+    /// WAV file played when unmuting, a built-in high tone when unset.
+    pub unmute_sound: Option<PathBuf>
+}
+
+impl FeedbackSettings {
+    /// Makes sure the sound won't be picked up by `source`, the source being muted.
+    /// The default sink is checked when the sound is played, see `heard_through`.
+    pub fn validate(&self, source: &str) -> Result<(), String> {
+        match &self.sink {
+            Some(sink) if Self::heard_through(sink, source) => {
+                Err(format!("feedback.sink `{}` would be heard through {}", sink, source))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Whether a sound played on `sink` is picked up by `source`: it's the sink itself, or its monitor.
+    pub fn heard_through(sink: &str, source: &str) -> bool {
+        sink == source || source == format!("{}.monitor", sink)
+    }
+}
+
+/// How mute changes are shown on the desktop, the `[notifications]` section of the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub urgency: Urgency,
+    /// How long the notification stays up, in milliseconds. -1 leaves it to the notification
+    /// server, 0 keeps it up until it's dismissed.
+    pub timeout_ms: i32
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self { urgency: Urgency::Normal, timeout_ms: -1 }
+    }
+}
+
+/// Urgency levels from the desktop notifications spec.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    #[default]
+    Normal,
+    Critical
+}
+
+impl Urgency {
+    /// The value of the `urgency` hint.
+    pub fn level(self) -> u8 {
+        match self {
+            Urgency::Low => 0,
+            Urgency::Normal => 1,
+            Urgency::Critical => 2
+        }
+    }
+}
+
+/// What to do when apps start or stop recording from the source, the `[call]` section of the
+/// config. A call starts when the first app starts recording and ends when the last one stops.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CallSettings {
+    /// Mute when a call ends.
+    pub auto_mute: bool,
+    /// Unmute when a call starts.
+    pub auto_unmute: bool,
+    /// Apps whose recording doesn't count, e.g. level meters. Matched case-insensitively.
+    pub ignore: Vec<String>,
+    /// Overrides for single apps, by name, matched case-insensitively.
+    pub apps: HashMap<String, AppRule>
+}
+
+/// Overrides `CallSettings` for one app, unset fields follow the section.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppRule {
+    pub auto_mute: Option<bool>,
+    pub auto_unmute: Option<bool>
+}
+
+impl CallSettings {
+    /// Whether recording by `app` counts towards a call.
+    pub fn counts(&self, app: &str) -> bool {
+        !self.ignore.iter().any(|ignored| ignored.eq_ignore_ascii_case(app))
+    }
+
+    /// The rule for `app`, if it has one.
+    pub fn rule(&self, app: &str) -> Option<&AppRule> {
+        self.apps.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(app))
+            .map(|(_, rule)| rule)
+    }
+
+    /// Whether a call `app` is in should unmute when it starts.
+    pub fn auto_unmute_for(&self, app: &str) -> bool {
+        self.rule(app).and_then(|rule| rule.auto_unmute).unwrap_or(self.auto_unmute)
+    }
+
+    /// Whether a call `app` was in should mute when it ends.
+    pub fn auto_mute_for(&self, app: &str) -> bool {
+        self.rule(app).and_then(|rule| rule.auto_mute).unwrap_or(self.auto_mute)
+    }
+}
+
+/// The value sent to the button for every status, the `[status]` section of the config.
+/// Left out, no status is sent, for firmware that only knows the mute variable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusMap {
+    pub muted: u8,
+    pub unmuted: u8,
+    /// The server is up, the audio source isn't there.
+    pub no_device: u8,
+    /// The PulseAudio server can't be reached.
+    pub server_down: u8,
+    /// Unmuted, and something is recording from the source.
+    pub in_call: u8,
+    /// The button is held down to talk.
+    pub push_to_talk: u8,
+    /// Muted because the screen is locked.
+    pub locked: u8
+}
+
+impl Default for StatusMap {
+    fn default() -> Self {
+        Self {
+            muted: 0x00,
+            unmuted: 0x01,
+            no_device: 0x02,
+            server_down: 0x03,
+            in_call: 0x04,
+            push_to_talk: 0x05,
+            locked: 0x06
+        }
+    }
+}
+
+/// Where the PulseAudio (or PipeWire) server is, the `[pulseaudio]` section of the config.
+/// Leaving it out uses the local default server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PulseAudioSettings {
+    /// Server address in libpulse's format, e.g. `tcp:audio-host:4713` or `unix:/run/user/1000/pulse/native`.
+    pub server: Option<String>,
+    /// Cookie to authenticate with, when the server wants one.
+    pub cookie: Option<PathBuf>
+}
+
+impl PulseAudioSettings {
+    /// Points libpulse at the configured server and cookie, through `PULSE_SERVER` and
+    /// `PULSE_COOKIE`, for every connection made afterwards. Call it before any threads are
+    /// started.
+    pub fn export(&self) {
+        if let Some(server) = &self.server {
+            env::set_var("PULSE_SERVER", server);
+        }
+        if let Some(cookie) = &self.cookie {
+            env::set_var("PULSE_COOKIE", cookie);
+        }
+    }
+
+    /// The server being talked to, for messages.
+    pub fn describe(&self) -> String {
+        match &self.server {
+            Some(server) => server.clone(),
+            None => "the local server".to_string()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.server {
+            Some(server) if server.trim().is_empty() => Err("pulseaudio.server is empty".to_string()),
+            _ => Ok(())
+        }
+    }
+}
+
+/// How to reach a button over the network, the `[tcp]` section of the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TcpSettings {
+    /// Whether we connect to the button, or it connects to us.
+    #[serde(default)]
+    pub mode: TcpMode,
+    /// `host:port` to connect to as a client, or to listen on as a server.
+    pub address: String,
+    /// Connect/write timeout, in milliseconds.
+    #[serde(default = "default_tcp_timeout_ms")]
+    pub timeout_ms: u64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TcpMode {
+    #[default]
+    Client,
+    Server
+}
+
+fn default_tcp_timeout_ms() -> u64 {
+    3000
+}
+
+impl TcpSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+/// Files written before the config was versioned are version 1.
+fn default_version() -> u32 {
+    1
+}
+
+impl Config {
+    /// Checks the values that can't be checked while deserializing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first invalid value.
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.tcp {
+            Some(tcp) if tcp.address.rsplit_once(':').is_none() => {
+                Err(format!("Invalid tcp.address `{}`, expected host:port", tcp.address))
+            },
+            Some(_) => Ok(()),
+            None if self.serial_port.is_empty() && self.device.is_none() => {
+                Err("Nothing to find the button by, set serial_port, [device] or [tcp]".to_string())
+            },
+            None => self.serial.validate()
+        }?;
+
+        if let Some(feedback) = &self.feedback {
+            feedback.validate(&self.audio_device_name)?;
+        }
+        for rule in &self.schedule {
+            rule.validate()?;
+        }
+        self.pulseaudio.validate()
+    }
+}
+
+/// Reads the config file at `path` on top of `SYSTEM_CONFIG_PATH` (when there is one), upgrading
+/// both to `CONFIG_VERSION` in memory if they're older. The files themselves are left alone, see
+/// `migrate_config` for that.
+/// `path` doesn't have to exist if the system config does.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or parsed, or holds invalid values.
+pub fn load_config(path: &Path) -> Result<Config, String> {
+    load_config_with_unknown_keys(path).map(|(config, _)| config)
+}
+
+/// Same as `load_config`, but also returns the keys that were ignored because they mean nothing
+/// to this version, as dotted paths (`serial.baudrate`).
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or parsed, or holds invalid values.
+pub fn load_config_with_unknown_keys(path: &Path) -> Result<(Config, Vec<String>), String> {
+    load_layered(path, Path::new(SYSTEM_CONFIG_PATH))
+}
+
+/// `load_config_with_unknown_keys` with `system` as the system config.
+fn load_layered(path: &Path, system: &Path) -> Result<(Config, Vec<String>), String> {
+    let mut table = if path != system && system.exists() {
+        read_table(system).map_err(|e| format!("{}: {}", system.display(), e))?
+    } else {
+        toml::Table::new()
+    };
+    if path.exists() || table.is_empty() {
+        merge(&mut table, read_table(path)?);
+    }
+
+    let mut unknown_keys: Vec<String> = vec![];
+    let config: Config = serde_ignored::deserialize(
+        toml::Value::Table(table),
+        |key| unknown_keys.push(key.to_string())
+    ).map_err(|e| format!("Unable to load data: {}", e))?;
+    config.validate()?;
+
+    Ok((config, unknown_keys))
+}
+
+/// Reads the config file at `path` as a table, upgraded to `CONFIG_VERSION`, for changing some
+/// keys without losing the others. Unlike `load_config` the system config isn't layered
+/// underneath, and a file that doesn't exist yet is an empty table.
+///
+/// # Errors
+///
+/// Returns an error if the file exists but can't be read or parsed.
+pub fn load_config_table(path: &Path) -> Result<toml::Table, String> {
+    if !path.exists() {
+        return Ok(toml::Table::new());
+    }
+
+    read_table(path)
+}
+
+/// Writes `table` to the config file at `path`, see `write_atomically`, creating its directory
+/// if need be.
+///
+/// # Errors
+///
+/// Returns an error if the table can't be serialized or the file can't be written.
+pub fn save_config_table(path: &Path, table: &toml::Table) -> Result<(), String> {
+    let content = toml::to_string(table)
+        .map_err(|e| format!("Unable to serialize config: {}", e))?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    write_atomically(path, &content)
+        .map_err(|e| format!("Could not write file: {}", e))
+}
+
+/// Replaces the file at `path` with `content` in one go: it's written to a temporary file next to
+/// it first, then renamed over it, so readers never see half a file and a failed write leaves the
+/// old one alone. A symlink is followed and the file it points to replaced, keeping the link, and
+/// the file keeps its permissions.
+///
+/// # Errors
+///
+/// Returns an error if the temporary file can't be written or renamed.
+pub fn write_atomically(path: &Path, content: &str) -> std::io::Result<()> {
+    // A link to a file that doesn't exist yet is left for the rename to replace.
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let permissions = std::fs::metadata(&path).map(|metadata| metadata.permissions()).ok();
+    let (temp, mut file) = create_temp_file(&path)?;
+
+    let result = file.write_all(content.as_bytes())
+        .and_then(|_| match permissions {
+            Some(permissions) => file.set_permissions(permissions),
+            None => Ok(())
+        })
+        .and_then(|_| file.sync_all())
+        .and_then(|_| std::fs::rename(&temp, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+
+    result
+}
+
+/// Creates a new file next to `path`, named after it and this process, that no one else is using.
+fn create_temp_file(path: &Path) -> std::io::Result<(PathBuf, std::fs::File)> {
+    let mut attempt = 0;
+    loop {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".{}.{}.tmp", std::process::id(), attempt));
+        let temp = PathBuf::from(temp);
+
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((temp, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Reads a config file as a table, upgraded to `CONFIG_VERSION`.
+fn read_table(path: &Path) -> Result<toml::Table, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read file: {}", e))?;
+    let mut table: toml::Table = toml::from_str(&content)
+        .map_err(|e| format!("Unable to load data: {}", e))?;
+    upgrade(&mut table)?;
+
+    Ok(table)
+}
+
+/// Layers `overlay` on top of `base`: sections are merged key by key, anything else in `overlay`
+/// replaces what's in `base`.
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        if let (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) = (base.get_mut(&key), &value) {
+            merge(base, overlay.clone());
+            continue;
+        }
+        base.insert(key, value);
+    }
+}
+
+/// Serial port parameters, the `[serial]` section of the config.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    /// Read/write timeout, in milliseconds.
+    pub timeout_ms: u64,
+    /// 5, 6, 7 or 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+    /// Assert (`true`) or clear (`false`) DTR once the port is open, leave it alone if unset.
+    /// Some boards only start talking once DTR is asserted.
+    pub dtr: Option<bool>,
+    /// Same as `dtr`, for RTS.
+    pub rts: Option<bool>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Odd,
+    Even
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    None,
+    Software,
+    Hardware
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115200,
+            timeout_ms: 300,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None
+        }
+    }
+}
+
+impl SerialSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Checks that the settings can be turned into a port configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data or stop bits are out of range.
+    pub fn validate(&self) -> Result<(), String> {
+        self.bits().map(|_| ())
+    }
+
+    fn bits(&self) -> Result<(serialport::DataBits, serialport::StopBits), String> {
+        let data_bits = match self.data_bits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            8 => serialport::DataBits::Eight,
+            bits => return Err(format!("Invalid data_bits {}, expected 5, 6, 7 or 8", bits))
+        };
+        let stop_bits = match self.stop_bits {
+            1 => serialport::StopBits::One,
+            2 => serialport::StopBits::Two,
+            bits => return Err(format!("Invalid stop_bits {}, expected 1 or 2", bits))
+        };
+
+        Ok((data_bits, stop_bits))
+    }
+
+    /// Creates a port builder for `port_path` with these settings.
+    /// DTR/RTS can only be set once the port is open, see `apply_control_lines`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data or stop bits are out of range.
+    pub fn builder(&self, port_path: &str) -> Result<SerialPortBuilder, String> {
+        let (data_bits, stop_bits) = self.bits()?;
+        let parity = match self.parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even
+        };
+        let flow_control = match self.flow_control {
+            FlowControl::None => serialport::FlowControl::None,
+            FlowControl::Software => serialport::FlowControl::Software,
+            FlowControl::Hardware => serialport::FlowControl::Hardware
+        };
+
+        Ok(
+            serialport::new(port_path, self.baud_rate)
+                .timeout(self.timeout())
+                .data_bits(data_bits)
+                .stop_bits(stop_bits)
+                .parity(parity)
+                .flow_control(flow_control)
+        )
+    }
+
+    /// Sets DTR/RTS on an open port, as configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the port refuses.
+    pub fn apply_control_lines(&self, port: &mut dyn serialport::SerialPort) -> serialport::Result<()> {
+        if let Some(dtr) = self.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = self.rts {
+            port.write_request_to_send(rts)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Identifies the button's USB serial device, so it's found wherever it ends up
+/// (`/dev/ttyACM0`, `/dev/ttyACM1`...). Every field that is set has to match.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSelector {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>
+}
+
+impl DeviceSelector {
+    /// Creates a selector matching exactly the given USB device.
+    pub fn from_usb_port_info(info: &UsbPortInfo) -> Self {
+        Self {
+            vendor_id: Some(info.vid),
+            product_id: Some(info.pid),
+            serial_number: info.serial_number.clone(),
+            manufacturer: info.manufacturer.clone(),
+            product: info.product.clone()
+        }
+    }
+
+    /// Whether nothing is set, in which case the selector matches nothing.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Reads a tty's USB identity from its udev properties, given by `property`, the same way
+    /// `serialport` fills in `UsbPortInfo`, so the daemon sees the names setup wrote down.
+    /// Returns `None` if it isn't a USB device.
+    pub fn from_udev_properties<'a>(property: impl Fn(&str) -> Option<&'a str>) -> Option<Self> {
+        // USB adapters behind some PCI devices have their properties prefixed with `ID_USB_`, and
+        // no names from the hardware database.
+        let (prefix, database) = match property("ID_BUS")? {
+            "usb" => ("ID_", true),
+            "pci" => ("ID_USB_", false),
+            _ => return None
+        };
+        let id = |name: &str| u16::from_str_radix(property(&format!("{}{}", prefix, name))?, 16).ok();
+        // Hex-escaped (`\x20` for a space) or with underscores for spaces, the device's own name
+        // wins over the database's.
+        let name = |name: &str| property(&format!("{}{}_ENC", prefix, name))
+            .map(unescape)
+            .or_else(|| property(&format!("{}{}", prefix, name)).map(str::to_string))
+            .map(|value| value.replace('_', " "))
+            .or_else(|| match database {
+                true => property(&format!("ID_{}_FROM_DATABASE", name)).map(str::to_string),
+                false => None
+            });
+
+        Some(Self {
+            vendor_id: Some(id("VENDOR_ID")?),
+            product_id: Some(id("MODEL_ID")?),
+            serial_number: property(&format!("{}SERIAL_SHORT", prefix)).map(str::to_string),
+            manufacturer: name("VENDOR"),
+            product: name("MODEL")
+        })
+    }
+
+    /// Whether the USB device matches every field that is set.
+    pub fn matches(&self, info: &UsbPortInfo) -> bool {
+        self.matches_identity(&Self::from_usb_port_info(info))
+    }
+
+    /// Whether the USB identity `device`, from `from_usb_port_info` or `from_udev_properties`,
+    /// matches every field that is set.
+    pub fn matches_identity(&self, device: &DeviceSelector) -> bool {
+        !self.is_empty()
+            && (self.vendor_id.is_none() || self.vendor_id == device.vendor_id)
+            && (self.product_id.is_none() || self.product_id == device.product_id)
+            && (self.serial_number.is_none() || self.serial_number == device.serial_number)
+            && (self.manufacturer.is_none() || self.manufacturer == device.manufacturer)
+            && (self.product.is_none() || self.product == device.product)
+    }
+
+    /// Looks through the serial ports currently available for the first one that matches, and
+    /// returns its path.
+    pub fn resolve(&self) -> Option<String> {
+        serialport::available_ports()
+            .ok()?
+            .into_iter()
+            .find(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => self.matches(info),
+                _ => false
+            })
+            .map(|port| port.port_name)
+    }
+}
+
+/// Undoes udev's `\xNN` escaping of property values.
+fn unescape(value: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = match tail {
+            [b'x', high, low, ..] if byte == b'\\' => std::str::from_utf8(&[*high, *low]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None
+        };
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[3..];
+            },
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut parts: Vec<String> = vec![];
+        match (self.vendor_id, self.product_id) {
+            (Some(vid), Some(pid)) => parts.push(format!("{:04x}:{:04x}", vid, pid)),
+            (Some(vid), None) => parts.push(format!("vendor {:04x}", vid)),
+            (None, Some(pid)) => parts.push(format!("product {:04x}", pid)),
+            (None, None) => {}
+        }
+        if let Some(manufacturer) = &self.manufacturer {
+            parts.push(manufacturer.clone());
+        }
+        if let Some(product) = &self.product {
+            parts.push(product.clone());
+        }
+        if let Some(serial_number) = &self.serial_number {
+            parts.push(format!("serial {}", serial_number));
+        }
+
+        write!(f, "USB device {}", parts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::{ symlink, PermissionsExt };
+
+    /// Loads `user` on top of `system`, both written to a temporary directory first when given.
+    fn load(user: Option<&str>, system: Option<&str>) -> Result<(Config, Vec<String>), String> {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, system_path) = (dir.path().join("config.toml"), dir.path().join("system.toml"));
+        if let Some(user) = user {
+            fs::write(&path, user).unwrap();
+        }
+        if let Some(system) = system {
+            fs::write(&system_path, system).unwrap();
+        }
+        load_layered(&path, &system_path)
+    }
+
+    fn table(content: &str) -> toml::Table {
+        toml::from_str(content).unwrap()
+    }
+
+    fn properties<'a>(properties: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<&'a str> {
+        move |name| properties.iter().find(|(key, _)| *key == name).map(|(_, value)| *value)
+    }
+
+    fn button() -> UsbPortInfo {
+        UsbPortInfo {
+            vid: 0x2341,
+            pid: 0x8036,
+            serial_number: Some("A1B2".to_string()),
+            manufacturer: Some("Mewture Labs".to_string()),
+            product: Some("Mute Button".to_string())
+        }
+    }
+
+    #[test]
+    fn selector_matches_every_field_set() {
+        let selector = DeviceSelector { vendor_id: Some(0x2341), serial_number: Some("A1B2".to_string()), ..Default::default() };
+        assert!(selector.matches(&button()));
+        assert!(!selector.matches(&UsbPortInfo { serial_number: None, ..button() }));
+        assert!(!selector.matches(&UsbPortInfo { vid: 0x1a86, ..button() }));
+        assert!(DeviceSelector::from_usb_port_info(&button()).matches(&button()));
+    }
+
+    #[test]
+    fn empty_selector_matches_nothing() {
+        assert!(!DeviceSelector::default().matches(&button()));
+        assert_eq!(DeviceSelector::default().resolve(), None);
+    }
+
+    #[test]
+    fn reads_udev_properties_like_serialport() {
+        let escaped = DeviceSelector::from_udev_properties(properties(&[
+            ("ID_BUS", "usb"),
+            ("ID_VENDOR_ID", "2341"),
+            ("ID_MODEL_ID", "8036"),
+            ("ID_SERIAL_SHORT", "A1B2"),
+            ("ID_VENDOR_ENC", "Mewture\\x20Labs"),
+            ("ID_VENDOR", "Mewture_Labs"),
+            ("ID_MODEL", "Mute_Button"),
+            ("ID_VENDOR_FROM_DATABASE", "Arduino SA")
+        ]));
+        assert_eq!(escaped, Some(DeviceSelector::from_usb_port_info(&button())));
+
+        let from_database = DeviceSelector::from_udev_properties(properties(&[
+            ("ID_BUS", "usb"),
+            ("ID_VENDOR_ID", "1a86"),
+            ("ID_MODEL_ID", "7523"),
+            ("ID_VENDOR_FROM_DATABASE", "QinHeng Electronics"),
+            ("ID_MODEL_FROM_DATABASE", "CH340 serial converter")
+        ])).unwrap();
+        assert_eq!(from_database.manufacturer.as_deref(), Some("QinHeng Electronics"));
+        assert_eq!(from_database.product.as_deref(), Some("CH340 serial converter"));
+    }
+
+    #[test]
+    fn reads_usb_devices_behind_pci() {
+        let device = DeviceSelector::from_udev_properties(properties(&[
+            ("ID_BUS", "pci"),
+            ("ID_USB_VENDOR_ID", "2341"),
+            ("ID_USB_MODEL_ID", "8036"),
+            ("ID_USB_SERIAL_SHORT", "A1B2"),
+            ("ID_USB_VENDOR_ENC", "Mewture\\x20Labs"),
+            ("ID_USB_MODEL_ENC", "Mute\\x20Button"),
+            ("ID_VENDOR_FROM_DATABASE", "Intel Corporation")
+        ]));
+        assert_eq!(device, Some(DeviceSelector::from_usb_port_info(&button())));
+
+        assert_eq!(DeviceSelector::from_udev_properties(properties(&[("ID_BUS", "pci")])), None);
+        assert_eq!(DeviceSelector::from_udev_properties(properties(&[("ID_VENDOR_ID", "2341")])), None);
+    }
+
+    #[test]
+    fn unescapes_udev_values() {
+        assert_eq!(unescape("Mewture\\x20Labs"), "Mewture Labs");
+        assert_eq!(unescape("caf\\xc3\\xa9"), "café");
+        assert_eq!(unescape("back\\slash\\x2"), "back\\slash\\x2");
+    }
+
+    #[test]
+    fn feedback_is_never_heard_through_the_source() {
+        assert!(FeedbackSettings::heard_through("speakers", "speakers.monitor"));
+        assert!(FeedbackSettings::heard_through("virtual", "virtual"));
+        assert!(!FeedbackSettings::heard_through("speakers", "mic"));
+
+        let settings = FeedbackSettings { sink: Some("speakers".to_string()), ..Default::default() };
+        assert!(settings.validate("speakers.monitor").is_err());
+        assert!(settings.validate("mic").is_ok());
+        assert!(FeedbackSettings::default().validate("speakers.monitor").is_ok());
+    }
+
+    #[test]
+    fn merges_sections_key_by_key() {
+        let mut base = table("a = 1\nb = 1\n[serial]\nbaud_rate = 9600\ntimeout_ms = 5\n");
+        merge(&mut base, table("b = 2\n[serial]\nbaud_rate = 115200\n[tcp]\naddress = \"h:1\"\n"));
+        assert_eq!(base, table("a = 1\nb = 2\n[serial]\nbaud_rate = 115200\ntimeout_ms = 5\n[tcp]\naddress = \"h:1\"\n"));
+    }
+
+    #[test]
+    fn replaces_arrays_and_values_of_another_type() {
+        let mut base = table("serial = 1\n[[schedule]]\nfrom = \"09:00\"\n[[schedule]]\nfrom = \"13:00\"\n");
+        merge(&mut base, table("[serial]\nbaud_rate = 1\n[[schedule]]\nfrom = \"22:00\"\n"));
+        assert_eq!(base, table("[serial]\nbaud_rate = 1\n[[schedule]]\nfrom = \"22:00\"\n"));
+    }
+
+    #[test]
+    fn user_config_wins_over_the_system_one() {
+        let (config, unknown) = load(
+            Some("audio_device_name = \"mine\"\n[serial]\nbaud_rate = 115200\n"),
+            Some("audio_device_name = \"theirs\"\nserial_port = \"/dev/ttyACM0\"\n[serial]\ntimeout_ms = 50\n")
+        ).unwrap();
+
+        assert_eq!(config.audio_device_name, "mine");
+        assert_eq!(config.serial_port, "/dev/ttyACM0");
+        assert_eq!(config.serial.baud_rate, 115200);
+        assert_eq!(config.serial.timeout_ms, 50);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn system_config_alone_is_enough() {
+        let (config, _) = load(None, Some("audio_device_name = \"mic\"\nserial_port = \"/dev/ttyACM0\"\n")).unwrap();
+        assert_eq!(config.audio_device_name, "mic");
+        assert!(load(None, None).is_err());
+    }
+
+    #[test]
+    fn reports_unknown_keys_from_either_file() {
+        let (_, unknown) = load(
+            Some("audio_device_name = \"mic\"\nserial_port = \"/dev/ttyACM0\"\ncolour = \"red\"\n"),
+            Some("[serial]\nbaudrate = 9600\n")
+        ).unwrap();
+        assert_eq!(unknown, vec!["colour".to_string(), "serial.baudrate".to_string()]);
+    }
+
+    #[test]
+    fn rejects_invalid_values_after_merging() {
+        let error = load(Some("audio_device_name = \"mic\"\n"), None).unwrap_err();
+        assert!(error.contains("Nothing to find the button by"), "{}", error);
+        assert!(load(Some("version = 99\naudio_device_name = \"mic\"\nserial_port = \"x\"\n"), None).is_err());
+    }
+
+    #[test]
+    fn creates_the_config_directory_when_saving() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mewture/config.toml");
+        save_config_table(&path, &table("audio_device_name = \"mic\"")).unwrap();
+        assert_eq!(table(&fs::read_to_string(&path).unwrap()), table("audio_device_name = \"mic\""));
+    }
+
+    #[test]
+    fn writes_a_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+
+        write_atomically(&path, "version = 2\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "version = 2\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn keeps_the_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomically(&path, "new").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn replaces_what_a_symlink_points_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("dotfiles.toml");
+        let link = dir.path().join("config.toml");
+        fs::write(&target, "old").unwrap();
+        symlink(&target, &link).unwrap();
+
+        write_atomically(&link, "new").unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
 }
@@ -0,0 +1,208 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::{ write_atomically, CONFIG_VERSION };
+
+/// Upgrades a config from the version before it to the next one, in place, returning whether
+/// anything but the version had to change.
+/// `MIGRATIONS[0]` takes version 1 to version 2, and so on.
+type Migration = fn(&mut Table) -> bool;
+
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize - 1] = [
+    v1_to_v2
+];
+
+/// Version 1 files had no `version` key, and every key added since has a default.
+fn v1_to_v2(_config: &mut Table) -> bool {
+    false
+}
+
+/// Which version a parsed config file is. Files written before versioning are version 1.
+///
+/// # Errors
+///
+/// Returns an error if `version` isn't a positive integer.
+pub fn version_of(config: &Table) -> Result<u32, String> {
+    match config.get("version") {
+        None => Ok(1),
+        Some(Value::Integer(version)) if *version >= 1 => u32::try_from(*version)
+            .map_err(|_| format!("Invalid config version {}", version)),
+        Some(version) => Err(format!("Invalid config version {}", version))
+    }
+}
+
+/// Brings a parsed config file up to `CONFIG_VERSION`, in place.
+/// Returns the version it started from if anything had to be done.
+///
+/// # Errors
+///
+/// Returns an error if the version is invalid or newer than this build understands.
+pub fn upgrade(config: &mut Table) -> Result<Option<u32>, String> {
+    upgrade_with(config, &MIGRATIONS).map(|(from, _)| from)
+}
+
+/// `upgrade` through `migrations`, also returning whether any of them changed more than the
+/// version.
+fn upgrade_with(config: &mut Table, migrations: &[Migration]) -> Result<(Option<u32>, bool), String> {
+    let from = version_of(config)?;
+    let to = migrations.len() as u32 + 1;
+    if from > to {
+        return Err(format!(
+            "Config version {} is newer than this version of Mewture supports ({})",
+            from,
+            to
+        ));
+    }
+    if from == to {
+        return Ok((None, false));
+    }
+
+    let mut changed = false;
+    for version in from..to {
+        changed |= migrations[version as usize - 1](config);
+        config.insert("version".to_string(), Value::Integer(i64::from(version + 1)));
+    }
+
+    Ok((Some(from), changed))
+}
+
+/// Where to keep a copy of the config file at `path`: next to it, as `<name>.<label>.bak`, or
+/// `<name>.<label>.<n>.bak` with the first `n` that's free if that's taken.
+pub fn backup_path(path: &Path, label: &str) -> PathBuf {
+    let named = |suffix: String| {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        PathBuf::from(backup)
+    };
+
+    let mut backup = named(format!(".{}.bak", label));
+    let mut n = 1;
+    while backup.symlink_metadata().is_ok() {
+        backup = named(format!(".{}.{}.bak", label, n));
+        n += 1;
+    }
+
+    backup
+}
+
+/// Upgrades the config file at `path` to `CONFIG_VERSION`, copying the old file next to it as
+/// `<name>.v<old version>.bak` first (see `backup_path`).
+/// Writing the file loses its comments and layout, so it's left alone unless a migration
+/// changed what it means: reading it upgrades it the same way every time.
+/// Returns the backup's path if the file was migrated, `None` if there was nothing to write.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read, parsed, backed up or written.
+pub fn migrate_config(path: &Path) -> Result<Option<PathBuf>, String> {
+    migrate_config_with(path, &MIGRATIONS)
+}
+
+/// `migrate_config` through `migrations`.
+fn migrate_config_with(path: &Path, migrations: &[Migration]) -> Result<Option<PathBuf>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read file: {}", e))?;
+    let mut config: Table = toml::from_str(&content)
+        .map_err(|e| format!("Unable to load data: {}", e))?;
+
+    let from = match upgrade_with(&mut config, migrations)? {
+        (Some(from), true) => from,
+        _ => return Ok(None)
+    };
+
+    let backup = backup_path(path, &format!("v{}", from));
+    fs::write(&backup, &content)
+        .map_err(|e| format!("Could not write backup {}: {}", backup.display(), e))?;
+
+    let upgraded = toml::to_string(&config)
+        .map_err(|e| format!("Unable to serialize config: {}", e))?;
+    write_atomically(path, &upgraded)
+        .map_err(|e| format!("Could not write file: {}", e))?;
+
+    Ok(Some(backup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(content: &str) -> Table {
+        toml::from_str(content).unwrap()
+    }
+
+    /// A migration that renames `port` to `serial_port`.
+    fn rename_port(config: &mut Table) -> bool {
+        match config.remove("port") {
+            Some(port) => {
+                config.insert("serial_port".to_string(), port);
+                true
+            },
+            None => false
+        }
+    }
+
+    #[test]
+    fn reads_the_version() {
+        assert_eq!(version_of(&table("")), Ok(1));
+        assert_eq!(version_of(&table("version = 3")), Ok(3));
+        assert!(version_of(&table("version = 0")).is_err());
+        assert!(version_of(&table("version = \"2\"")).is_err());
+        assert!(version_of(&table("version = 5000000000")).is_err());
+    }
+
+    #[test]
+    fn upgrades_in_memory() {
+        let mut config = table("audio_device_name = \"mic\"");
+        assert_eq!(upgrade(&mut config), Ok(Some(1)));
+        assert_eq!(version_of(&config), Ok(CONFIG_VERSION));
+        assert_eq!(upgrade(&mut config), Ok(None));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut config = table(&format!("version = {}", CONFIG_VERSION + 1));
+        assert!(upgrade(&mut config).unwrap_err().contains("newer"));
+    }
+
+    #[test]
+    fn leaves_a_file_alone_when_only_the_version_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let content = "# My button\naudio_device_name = \"mic\"\n";
+        fs::write(&path, content).unwrap();
+
+        assert_eq!(migrate_config(&path), Ok(None));
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rewrites_a_file_a_migration_changed_and_backs_it_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "port = \"/dev/ttyACM0\"\n").unwrap();
+        fs::write(dir.path().join("config.toml.v1.bak"), "older").unwrap();
+
+        let backup = migrate_config_with(&path, &[rename_port]).unwrap().unwrap();
+
+        assert_eq!(backup, dir.path().join("config.toml.v1.1.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "port = \"/dev/ttyACM0\"\n");
+        assert_eq!(fs::read_to_string(dir.path().join("config.toml.v1.bak")).unwrap(), "older");
+        let migrated = table(&fs::read_to_string(&path).unwrap());
+        assert_eq!(migrated.get("serial_port").and_then(Value::as_str), Some("/dev/ttyACM0"));
+        assert_eq!(version_of(&migrated), Ok(2));
+        assert_eq!(migrate_config_with(&path, &[rename_port]), Ok(None));
+    }
+
+    #[test]
+    fn names_backups_after_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert_eq!(backup_path(&path, "broken"), dir.path().join("config.toml.broken.bak"));
+
+        fs::write(dir.path().join("config.toml.broken.bak"), "").unwrap();
+        fs::write(dir.path().join("config.toml.broken.1.bak"), "").unwrap();
+        assert_eq!(backup_path(&path, "broken"), dir.path().join("config.toml.broken.2.bak"));
+    }
+}
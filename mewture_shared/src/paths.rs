@@ -0,0 +1,161 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// System-wide config, read underneath the user's config so it can supply defaults.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/mewture/config.toml";
+
+/// Environment variable naming the config file, for when `--config` isn't given.
+pub const CONFIG_ENV: &str = "MEWTURE_CONFIG";
+
+/// Where the user's config file is, in order of preference:
+///
+/// 1. `explicit`, from `--config`.
+/// 2. `$MEWTURE_CONFIG`.
+/// 3. `$XDG_CONFIG_HOME/mewture/config.toml` (`~/.config/mewture/config.toml`), if it exists.
+/// 4. `~/.mewture/config.toml`, where older versions kept it, if it exists.
+/// 5. The XDG path from 3, for a config that's yet to be written.
+///
+/// # Errors
+///
+/// Returns an error if the home directory is needed and can't be found.
+pub fn config_path(explicit: Option<&Path>) -> Result<PathBuf, String> {
+    if let Some(path) = explicit {
+        return Ok(path.to_path_buf());
+    }
+    if let Some(path) = env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+
+    let xdg = xdg_config_path()?;
+    if xdg.exists() {
+        return Ok(xdg);
+    }
+
+    let legacy = legacy_config_path()?;
+    if legacy.exists() {
+        return Ok(legacy);
+    }
+
+    Ok(xdg)
+}
+
+/// `$XDG_CONFIG_HOME`, defaulting to `~/.config`.
+/// Relative `XDG_CONFIG_HOME`s are ignored, as the spec says.
+///
+/// # Errors
+///
+/// Returns an error if the home directory is needed and can't be found.
+pub fn config_home() -> Result<PathBuf, String> {
+    match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => Ok(path),
+        _ => Ok(home_dir()?.join(".config"))
+    }
+}
+
+/// `$XDG_STATE_HOME`, defaulting to `~/.local/state`.
+/// Relative `XDG_STATE_HOME`s are ignored, as the spec says.
+///
+/// # Errors
+///
+/// Returns an error if the home directory is needed and can't be found.
+pub fn state_home() -> Result<PathBuf, String> {
+    match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(path) if path.is_absolute() => Ok(path),
+        _ => Ok(home_dir()?.join(".local/state"))
+    }
+}
+
+/// `$XDG_STATE_HOME/mewture/state`, where the daemon keeps the last mute state.
+///
+/// # Errors
+///
+/// Returns an error if the home directory is needed and can't be found.
+pub fn state_path() -> Result<PathBuf, String> {
+    Ok(state_home()?.join("mewture/state"))
+}
+
+/// `$XDG_CONFIG_HOME/mewture/config.toml`.
+fn xdg_config_path() -> Result<PathBuf, String> {
+    Ok(config_home()?.join("mewture/config.toml"))
+}
+
+/// `~/.mewture/config.toml`.
+fn legacy_config_path() -> Result<PathBuf, String> {
+    Ok(home_dir()?.join(".mewture/config.toml"))
+}
+
+fn home_dir() -> Result<PathBuf, String> {
+    home::home_dir().ok_or_else(|| "Failed to get home directory".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test thread.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Points `HOME` and `XDG_CONFIG_HOME` into `dir`, with `MEWTURE_CONFIG` set to `config_env`,
+    /// and creates the given config files in there.
+    fn home(dir: &Path, config_env: Option<&str>, existing: &[&str]) {
+        env::set_var("HOME", dir.join("home"));
+        env::set_var("XDG_CONFIG_HOME", dir.join("xdg"));
+        match config_env {
+            Some(path) => env::set_var(CONFIG_ENV, path),
+            None => env::remove_var(CONFIG_ENV)
+        }
+        for file in existing {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn prefers_the_explicit_path() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        home(dir.path(), Some("/from/env.toml"), &["xdg/mewture/config.toml"]);
+        assert_eq!(config_path(Some(Path::new("given.toml"))).unwrap(), PathBuf::from("given.toml"));
+    }
+
+    #[test]
+    fn then_the_environment() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        home(dir.path(), Some("/from/env.toml"), &["xdg/mewture/config.toml"]);
+        assert_eq!(config_path(None).unwrap(), PathBuf::from("/from/env.toml"));
+
+        home(dir.path(), Some(""), &[]);
+        assert_eq!(config_path(None).unwrap(), dir.path().join("xdg/mewture/config.toml"));
+    }
+
+    #[test]
+    fn then_an_existing_xdg_config_over_the_legacy_one() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        home(dir.path(), None, &["xdg/mewture/config.toml", "home/.mewture/config.toml"]);
+        assert_eq!(config_path(None).unwrap(), dir.path().join("xdg/mewture/config.toml"));
+    }
+
+    #[test]
+    fn then_an_existing_legacy_config() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        home(dir.path(), None, &["home/.mewture/config.toml"]);
+        assert_eq!(config_path(None).unwrap(), dir.path().join("home/.mewture/config.toml"));
+    }
+
+    #[test]
+    fn defaults_to_the_xdg_config() {
+        let _env = ENV.lock().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        home(dir.path(), None, &[]);
+        assert_eq!(config_path(None).unwrap(), dir.path().join("xdg/mewture/config.toml"));
+
+        env::set_var("XDG_CONFIG_HOME", "relative");
+        assert_eq!(config_path(None).unwrap(), dir.path().join("home/.config/mewture/config.toml"));
+    }
+}